@@ -0,0 +1,186 @@
+use std::hash::{Hash, Hasher};
+
+use crate::{
+    constant::PI,
+    mie::{LogNormal, MieTables},
+    sampling::MiePhase,
+};
+
+// vertical distribution of the particles of a layer, altitudes [km]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Profile {
+    Exponential { scale_height: f64 },      // densest at the ground
+    Gaussian { altitude: f64, width: f64 }, // densest at the altitude, width: standard deviation
+    Slab { base: f64, top: f64 },
+}
+
+impl Profile {
+    // 0 - 1
+    pub fn density(&self, h: f64) -> f64 {
+        match *self {
+            Profile::Exponential { scale_height } => (-h / scale_height).exp(),
+            Profile::Gaussian { altitude, width } => {
+                (-0.5 * ((h - altitude) / width).powi(2)).exp()
+            }
+            Profile::Slab { base, top } => {
+                if (base..=top).contains(&h) {
+                    1.
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+
+    // the largest between the altitudes
+    pub fn max_density(&self, low: f64, high: f64) -> f64 {
+        match *self {
+            Profile::Exponential { .. } => self.density(low),
+            Profile::Gaussian { altitude, .. } => self.density(altitude.clamp(low, high)),
+            Profile::Slab { base, top } => {
+                if low <= top && high >= base {
+                    1.
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+
+    // integral of the density over the altitude [km]
+    pub fn thickness(&self) -> f64 {
+        match *self {
+            Profile::Exponential { scale_height } => scale_height,
+            Profile::Gaussian { width, .. } => width * (2. * PI).sqrt(),
+            Profile::Slab { base, top } => top - base,
+        }
+    }
+}
+
+impl Hash for Profile {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            Profile::Exponential { scale_height } => {
+                0.hash(state);
+                scale_height.to_bits().hash(state);
+            }
+            Profile::Gaussian { altitude, width } => {
+                1.hash(state);
+                altitude.to_bits().hash(state);
+                width.to_bits().hash(state);
+            }
+            Profile::Slab { base, top } => {
+                2.hash(state);
+                base.to_bits().hash(state);
+                top.to_bits().hash(state);
+            }
+        }
+    }
+}
+
+/*
+particles of one kind, the coefficients scale with the wavelength
+by the Angstrom exponents, (wavelength / 550nm)^-angstrom
+*/
+pub struct AerosolLayer {
+    pub profile: Profile,
+    pub scattering: f64, // at the densest at 550nm [1/km]
+    pub absorption: f64, // [1/km]
+    pub angstrom: f64,   // of the scattering
+    pub absorption_angstrom: f64,
+    pub phase: MiePhase,
+}
+
+impl AerosolLayer {
+    pub fn new(profile: Profile, scattering: f64, absorption: f64) -> Self {
+        AerosolLayer {
+            profile,
+            scattering,
+            absorption,
+            angstrom: 0.,
+            absorption_angstrom: 0.,
+            phase: MiePhase::new(),
+        }
+    }
+
+    // haze near the ground
+    pub fn boundary_layer() -> Self {
+        AerosolLayer::new(Profile::Exponential { scale_height: 1.2 }, 4e-3, 4.4e-3)
+    }
+
+    // thin background above the boundary layer
+    pub fn free_troposphere() -> Self {
+        AerosolLayer {
+            angstrom: 1.3,
+            absorption_angstrom: 1.,
+            phase: MiePhase::HenyeyGreenstein { g: 0.7 },
+            ..AerosolLayer::new(Profile::Slab { base: 2., top: 10. }, 2e-3, 1e-4)
+        }
+    }
+
+    // sulfate droplets after an eruption, optical_depth: vertical at 550nm
+    pub fn volcanic(optical_depth: f64) -> Self {
+        let profile = Profile::Gaussian {
+            altitude: 20.,
+            width: 2.5,
+        };
+        let scattering = optical_depth / profile.thickness();
+        let droplets = LogNormal {
+            median_radius: 0.35,
+            geometric_std: 1.4,
+        };
+        AerosolLayer {
+            angstrom: 1.,
+            absorption_angstrom: 1.,
+            phase: MiePhase::LorenzMie(MieTables::new(droplets, (1.43, 1e-7))),
+            ..AerosolLayer::new(profile, scattering, scattering * 1e-3)
+        }
+    }
+
+    // mineral dust lifted over the boundary layer, absorbing more in the blue
+    pub fn saharan_dust(optical_depth: f64) -> Self {
+        let profile = Profile::Slab {
+            base: 1.5,
+            top: 5.5,
+        };
+        let extinction = optical_depth / profile.thickness();
+        AerosolLayer {
+            angstrom: 0.2,
+            absorption_angstrom: 2.5,
+            phase: MiePhase::HenyeyGreenstein { g: 0.75 },
+            ..AerosolLayer::new(profile, extinction * 0.9, extinction * 0.1)
+        }
+    }
+
+    // (scattering, absorption) [1/km] at the altitude [km]
+    pub fn coeff(&self, h: f64, wavelength: f64) -> (f64, f64) {
+        let density = self.profile.density(h);
+        if density == 0. {
+            return (0., 0.);
+        }
+        let ratio = wavelength / 550.;
+        (
+            self.scattering * density * ratio.powf(-self.angstrom),
+            self.absorption * density * ratio.powf(-self.absorption_angstrom),
+        )
+    }
+
+    // the largest extinction between the altitudes
+    pub fn max_extinction(&self, low: f64, high: f64, wavelength: f64) -> f64 {
+        let ratio = wavelength / 550.;
+        self.profile.max_density(low, high)
+            * (self.scattering * ratio.powf(-self.angstrom)
+                + self.absorption * ratio.powf(-self.absorption_angstrom))
+    }
+}
+
+impl Hash for AerosolLayer {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.profile.hash(state);
+        self.scattering.to_bits().hash(state);
+        self.absorption.to_bits().hash(state);
+        self.angstrom.to_bits().hash(state);
+        self.absorption_angstrom.to_bits().hash(state);
+        self.phase.hash(state);
+    }
+}
@@ -0,0 +1,380 @@
+use crate::{
+    constant::{EPS, PI, PI_INV},
+    math::{Vec3, cross, dot, fmax},
+    polarization::Mueller,
+    random::XorRand,
+    sampling::{pdf_sample_cos_hemi, sample_cos_hemisphere},
+};
+
+// reflection of an opaque surface at one wavelength
+// normal: shading normal, wo: to the viewer, wi: to the light, pointing away from the surface
+pub trait Bsdf {
+    fn eval(&self, normal: &Vec3, wo: &Vec3, wi: &Vec3) -> f64;
+    // None if the sampled direction goes under the surface
+    fn sample(&self, normal: &Vec3, wo: &Vec3, rand: &mut XorRand) -> Option<Vec3>;
+    fn pdf(&self, normal: &Vec3, wo: &Vec3, wi: &Vec3) -> f64;
+    // relative to eval(), x perpendicular to the plane of wo and wi, depolarizing by default
+    fn mueller(&self, _normal: &Vec3, _wo: &Vec3, _wi: &Vec3) -> Mueller {
+        Mueller::depolarizer()
+    }
+}
+
+pub struct Lambertian {
+    pub reflectance: f64,
+}
+
+impl Bsdf for Lambertian {
+    fn eval(&self, normal: &Vec3, wo: &Vec3, wi: &Vec3) -> f64 {
+        if dot(*normal, *wo) <= 0. || dot(*normal, *wi) <= 0. {
+            return 0.;
+        }
+        self.reflectance * PI_INV
+    }
+
+    fn sample(&self, normal: &Vec3, _wo: &Vec3, rand: &mut XorRand) -> Option<Vec3> {
+        Some(sample_cos_hemisphere(normal, rand))
+    }
+
+    fn pdf(&self, normal: &Vec3, _wo: &Vec3, wi: &Vec3) -> f64 {
+        pdf_sample_cos_hemi(normal, wi)
+    }
+}
+
+/*
+rough diffuse surface, sigma: standard deviation of the facet slope angle [rad]
+
+Michael Oren and Shree K. Nayar. Generalization of Lambert's Reflectance
+Model. In: SIGGRAPH '94, pp. 239–246.
+*/
+pub struct OrenNayar {
+    pub reflectance: f64,
+    pub sigma: f64,
+}
+
+impl Bsdf for OrenNayar {
+    fn eval(&self, normal: &Vec3, wo: &Vec3, wi: &Vec3) -> f64 {
+        let cos_o = dot(*normal, *wo);
+        let cos_i = dot(*normal, *wi);
+        if cos_o <= 0. || cos_i <= 0. {
+            return 0.;
+        }
+
+        let sigma2 = self.sigma * self.sigma;
+        let a = 1. - 0.5 * sigma2 / (sigma2 + 0.33);
+        let b = 0.45 * sigma2 / (sigma2 + 0.09);
+
+        // cosine of the azimuth difference
+        let po = *wo - *normal * cos_o;
+        let pi = *wi - *normal * cos_i;
+        let cos_phi = if po.length() < EPS || pi.length() < EPS {
+            0.
+        } else {
+            fmax(dot(po.normalize(), pi.normalize()), 0.)
+        };
+
+        let sin_o = (1. - cos_o * cos_o).max(0.).sqrt();
+        let sin_i = (1. - cos_i * cos_i).max(0.).sqrt();
+        let (sin_alpha, tan_beta) = if cos_i > cos_o {
+            (sin_o, sin_i / cos_i)
+        } else {
+            (sin_i, sin_o / cos_o)
+        };
+
+        self.reflectance * PI_INV * (a + b * cos_phi * sin_alpha * tan_beta)
+    }
+
+    fn sample(&self, normal: &Vec3, _wo: &Vec3, rand: &mut XorRand) -> Option<Vec3> {
+        Some(sample_cos_hemisphere(normal, rand))
+    }
+
+    fn pdf(&self, normal: &Vec3, _wo: &Vec3, wi: &Vec3) -> f64 {
+        pdf_sample_cos_hemi(normal, wi)
+    }
+}
+
+pub enum Fresnel {
+    Dielectric { ior: f64 },
+    Conductor { ior: f64, k: f64 },
+    Constant { reflectance: f64 }, // independent of the angle
+}
+
+// Beckmann facets, alpha: root mean square slope
+pub struct Microfacet {
+    pub alpha: f64,
+    pub fresnel: Fresnel,
+}
+
+impl Microfacet {
+    pub fn dielectric(alpha: f64, ior: f64) -> Self {
+        Microfacet {
+            alpha,
+            fresnel: Fresnel::Dielectric { ior },
+        }
+    }
+
+    pub fn conductor(alpha: f64, ior: f64, k: f64) -> Self {
+        Microfacet {
+            alpha,
+            fresnel: Fresnel::Conductor { ior, k },
+        }
+    }
+
+    pub fn constant(alpha: f64, reflectance: f64) -> Self {
+        Microfacet {
+            alpha,
+            fresnel: Fresnel::Constant { reflectance },
+        }
+    }
+
+    fn sigma2(&self) -> f64 {
+        self.alpha.max(1e-3).powi(2)
+    }
+
+    pub fn fresnel(&self, cos_i: f64) -> f64 {
+        match self.fresnel {
+            Fresnel::Dielectric { ior } => fresnel_dielectric(cos_i, ior),
+            Fresnel::Conductor { ior, k } => fresnel_conductor(cos_i, ior, k),
+            Fresnel::Constant { reflectance } => reflectance,
+        }
+    }
+}
+
+impl Bsdf for Microfacet {
+    fn eval(&self, normal: &Vec3, wo: &Vec3, wi: &Vec3) -> f64 {
+        let cos_o = dot(*normal, *wo);
+        let cos_i = dot(*normal, *wi);
+        if cos_o <= 0. || cos_i <= 0. {
+            return 0.;
+        }
+
+        let h = (*wo + *wi).normalize();
+        let sigma2 = self.sigma2();
+        self.fresnel(dot(*wi, h))
+            * beckmann(dot(*normal, h), sigma2)
+            * smith(cos_o, sigma2)
+            * smith(cos_i, sigma2)
+            / (4. * cos_i * cos_o)
+    }
+
+    fn sample(&self, normal: &Vec3, wo: &Vec3, rand: &mut XorRand) -> Option<Vec3> {
+        let h = sample_beckmann(normal, self.sigma2(), rand);
+        let wi = -*wo + h * (2. * dot(*wo, h));
+        if dot(*normal, wi) > 0. {
+            Some(wi)
+        } else {
+            None
+        }
+    }
+
+    fn pdf(&self, normal: &Vec3, wo: &Vec3, wi: &Vec3) -> f64 {
+        if dot(*normal, *wo) <= 0. || dot(*normal, *wi) <= 0. {
+            return 0.;
+        }
+        let h = (*wo + *wi).normalize();
+        let cos_h = dot(*normal, h);
+        beckmann(cos_h, self.sigma2()) * cos_h / (4. * dot(*wo, h))
+    }
+
+    // reflection on the facet between wo and wi
+    fn mueller(&self, _normal: &Vec3, wo: &Vec3, wi: &Vec3) -> Mueller {
+        match self.fresnel {
+            Fresnel::Dielectric { ior } => {
+                let h = (*wo + *wi).normalize();
+                let (rs, rp) = fresnel_amplitudes(dot(*wi, h), ior);
+                Mueller::fresnel(rs, rp)
+            }
+            // the polarization is kept as by a mirror
+            _ => Mueller::fresnel(-1., 1.),
+        }
+    }
+}
+
+// weight * a + (1 - weight) * b
+pub struct Mix {
+    pub a: Box<dyn Bsdf>,
+    pub b: Box<dyn Bsdf>,
+    pub weight: f64,
+}
+
+impl Bsdf for Mix {
+    fn eval(&self, normal: &Vec3, wo: &Vec3, wi: &Vec3) -> f64 {
+        self.weight * self.a.eval(normal, wo, wi) + (1. - self.weight) * self.b.eval(normal, wo, wi)
+    }
+
+    fn sample(&self, normal: &Vec3, wo: &Vec3, rand: &mut XorRand) -> Option<Vec3> {
+        if rand.next01() < self.weight {
+            self.a.sample(normal, wo, rand)
+        } else {
+            self.b.sample(normal, wo, rand)
+        }
+    }
+
+    fn pdf(&self, normal: &Vec3, wo: &Vec3, wi: &Vec3) -> f64 {
+        self.weight * self.a.pdf(normal, wo, wi) + (1. - self.weight) * self.b.pdf(normal, wo, wi)
+    }
+
+    fn mueller(&self, normal: &Vec3, wo: &Vec3, wi: &Vec3) -> Mueller {
+        let a = self.weight * self.a.eval(normal, wo, wi);
+        let b = (1. - self.weight) * self.b.eval(normal, wo, wi);
+        if a + b <= 0. {
+            return Mueller::depolarizer();
+        }
+        (self.a.mueller(normal, wo, wi) * a + self.b.mueller(normal, wo, wi) * b) * (1. / (a + b))
+    }
+}
+
+// amplitude coefficients (rs, rp) of the reflection from air
+pub fn fresnel_amplitudes(cos_i: f64, ior: f64) -> (f64, f64) {
+    let cos_i = cos_i.clamp(0., 1.);
+    let sin_t2 = (1. - cos_i * cos_i) / (ior * ior);
+    let cos_t = (1. - sin_t2).sqrt();
+    let rs = (cos_i - ior * cos_t) / (cos_i + ior * cos_t);
+    let rp = (ior * cos_i - cos_t) / (ior * cos_i + cos_t);
+    (rs, rp)
+}
+
+// unpolarized reflectance from air
+pub fn fresnel_dielectric(cos_i: f64, ior: f64) -> f64 {
+    let (rs, rp) = fresnel_amplitudes(cos_i, ior);
+    0.5 * (rs * rs + rp * rp)
+}
+
+pub fn fresnel_conductor(cos_i: f64, ior: f64, k: f64) -> f64 {
+    let cos_i = cos_i.clamp(0., 1.);
+    let cos2 = cos_i * cos_i;
+    let t0 = ior * ior + k * k;
+    let t1 = t0 * cos2;
+    let rs = (t0 - 2. * ior * cos_i + cos2) / (t0 + 2. * ior * cos_i + cos2);
+    let rp = (t1 - 2. * ior * cos_i + 1.) / (t1 + 2. * ior * cos_i + 1.);
+    0.5 * (rs + rp)
+}
+
+fn beckmann(cos_h: f64, sigma2: f64) -> f64 {
+    if cos_h <= 0. {
+        return 0.;
+    }
+    let cos2 = cos_h * cos_h;
+    let tan2 = (1. - cos2) / cos2;
+    (-tan2 / sigma2).exp() / (PI * sigma2 * cos2 * cos2)
+}
+
+// rational approximation of the Smith shadowing function for Beckmann
+fn smith(cos: f64, sigma2: f64) -> f64 {
+    let tan = (1. - cos * cos).max(0.).sqrt() / cos;
+    if tan < EPS {
+        return 1.;
+    }
+    let a = 1. / (sigma2.sqrt() * tan);
+    if a >= 1.6 {
+        return 1.;
+    }
+    (3.535 * a + 2.181 * a * a) / (1. + 2.276 * a + 2.577 * a * a)
+}
+
+// facet normal, pdf is beckmann() * cos_h
+fn sample_beckmann(normal: &Vec3, sigma2: f64, rand: &mut XorRand) -> Vec3 {
+    let w = *normal;
+    let u = if w.0.abs() > EPS {
+        cross(Vec3(0., 1., 0.), w).normalize()
+    } else {
+        cross(Vec3(1., 0., 0.), w).normalize()
+    };
+    let v = cross(w, u);
+
+    let phi = 2. * PI * rand.next01();
+    let tan2 = -sigma2 * (1. - rand.next01()).ln();
+    let cos_theta = 1. / (1. + tan2).sqrt();
+    let sin_theta = (1. - cos_theta * cos_theta).sqrt();
+
+    u * sin_theta * phi.cos() + v * sin_theta * phi.sin() + w * cos_theta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COS_BINS: usize = 8;
+    const PHI_BINS: usize = 16;
+
+    fn direction(cos_theta: f64, phi: f64) -> Vec3 {
+        let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+        Vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+    }
+
+    fn bin(wi: &Vec3) -> usize {
+        let c = ((wi.2 * COS_BINS as f64) as usize).min(COS_BINS - 1);
+        let phi = wi.1.atan2(wi.0).rem_euclid(2. * PI);
+        let p = ((phi / (2. * PI) * PHI_BINS as f64) as usize).min(PHI_BINS - 1);
+        c * PHI_BINS + p
+    }
+
+    // the directions drawn by sample() are distributed as pdf() over bins of the hemisphere
+    fn assert_sampled_by_pdf(bsdf: &dyn Bsdf) {
+        let normal = Vec3(0., 0., 1.);
+        let wo = direction(0.8, 0.3);
+
+        let sub = 8;
+        let mut expected = vec![0.; COS_BINS * PHI_BINS];
+        for (i, e) in expected.iter_mut().enumerate() {
+            let (c, p) = (i / PHI_BINS, i % PHI_BINS);
+            for (j, k) in (0..sub * sub).map(|n| (n / sub, n % sub)) {
+                let cos_theta = (c as f64 + (j as f64 + 0.5) / sub as f64) / COS_BINS as f64;
+                let phi = (p as f64 + (k as f64 + 0.5) / sub as f64) / PHI_BINS as f64 * 2. * PI;
+                let d_omega = 2. * PI / (COS_BINS * PHI_BINS * sub * sub) as f64;
+                *e += bsdf.pdf(&normal, &wo, &direction(cos_theta, phi)) * d_omega;
+            }
+        }
+
+        let n = 200000;
+        let mut counts = vec![0; COS_BINS * PHI_BINS];
+        let mut rand = XorRand::new(5);
+        for _ in 0..n {
+            if let Some(wi) = bsdf.sample(&normal, &wo, &mut rand)
+                && wi.2 > 0.
+            {
+                counts[bin(&wi)] += 1;
+            }
+        }
+
+        for (count, e) in counts.iter().zip(&expected) {
+            let freq = *count as f64 / n as f64;
+            let tolerance = 5. * (e / n as f64).sqrt() + 2e-3;
+            assert!((freq - e).abs() < tolerance, "{freq} {e}");
+        }
+    }
+
+    #[test]
+    fn sample_matches_pdf() {
+        assert_sampled_by_pdf(&Lambertian { reflectance: 0.5 });
+        assert_sampled_by_pdf(&OrenNayar {
+            reflectance: 0.5,
+            sigma: 0.4,
+        });
+        assert_sampled_by_pdf(&Microfacet::dielectric(0.4, 1.5));
+        assert_sampled_by_pdf(&Mix {
+            a: Box::new(Lambertian { reflectance: 0.5 }),
+            b: Box::new(Microfacet::conductor(0.3, 0.2, 3.)),
+            weight: 0.6,
+        });
+    }
+
+    // a white Lambertian surface reflects all the light
+    #[test]
+    fn lambertian_white_furnace() {
+        let bsdf = Lambertian { reflectance: 1. };
+        let normal = Vec3(0., 0., 1.);
+        let wo = direction(0.5, 1.);
+        let mut rand = XorRand::new(9);
+
+        let n = 10000;
+        let mut sum = 0.;
+        for _ in 0..n {
+            let wi = bsdf.sample(&normal, &wo, &mut rand).unwrap();
+            let pdf = bsdf.pdf(&normal, &wo, &wi);
+            if pdf > 0. {
+                sum += bsdf.eval(&normal, &wo, &wi) * dot(normal, wi) / pdf;
+            }
+        }
+        assert!((sum / n as f64 - 1.).abs() < 1e-3);
+    }
+}
@@ -1,7 +1,14 @@
+use std::hash::{Hash, Hasher};
+
 use crate::{
-    constant::{AXIS, EARTH_RAD, EARTH_TO_SUN, EPS, PI, SUN_RAD},
+    constant::{EARTH_RAD, EARTH_TO_SUN, EPS, PI, SUN_RAD},
+    error::{Error, Result},
     math::{Point3, Vec3, cross, to_radian},
+    polarization::orthogonalize,
+    sampling::WavelengthSampling,
     scene::Scene,
+    spectrum::SpectralResponse,
+    sphere::earth_axis,
 };
 
 #[allow(unused)]
@@ -14,7 +21,7 @@ pub enum Direction {
 
 pub struct Eye {
     time: f64,            // 0 - 24 [h]
-    latitude: f64,        // north latitude: 0 - 90, south latitude: -90 - 0
+    latitude: f64,        // geodetic, north latitude: 0 - 90, south latitude: -90 - 0
     altitude: f64,        // [km]
     direction: Direction, // NSWE
     elevation: f64,       // -90 - 90
@@ -38,30 +45,30 @@ impl Eye {
     }
 
     fn get_position(&self, scene: &mut Scene) -> Point3 {
-        let w = Vec3(0., to_radian(AXIS + 90.).cos(), to_radian(AXIS + 90.).sin());
+        let w = earth_axis();
         let u =
             (scene.earth.shape.center - scene.sun.center) / (SUN_RAD + EARTH_RAD + EARTH_TO_SUN);
         let v = cross(w, u).normalize();
         let u = cross(v, w).normalize();
 
         let phi = PI * self.time / 12.;
-        let theta = to_radian(90. - self.latitude);
-        let r = EARTH_RAD + self.altitude;
+        let (rho, z) = scene
+            .earth
+            .shape
+            .meridian(to_radian(self.latitude), self.altitude);
 
         let jp_phi = phi + PI / 4.;
         scene.earth.u = u * jp_phi.cos() + v * jp_phi.sin();
         scene.earth.v = u * (jp_phi + PI / 2.).cos() + v * (jp_phi + PI / 2.).sin();
         scene.earth.w = w;
 
-        r * (u * theta.sin() * phi.cos() + v * theta.sin() * phi.sin() + w * theta.cos())
-            + scene.earth.shape.center
+        rho * (u * phi.cos() + v * phi.sin()) + w * z + scene.earth.shape.center
     }
 
     fn get_direction(&self, scene: &Scene, pos: &Point3) -> Vec3 {
         // can not calculate if eys is on the N/S Pole
-        let w = (*pos - scene.earth.shape.center).normalize();
-        let axis = Vec3(0., to_radian(AXIS + 90.).cos(), to_radian(AXIS + 90.).sin());
-        let u = cross(axis, w).normalize(); // east
+        let w = scene.earth.shape.normal(pos);
+        let u = cross(earth_axis(), w).normalize(); // east
         let v = cross(w, u); // north
 
         let theta = to_radian(90. - self.elevation);
@@ -87,9 +94,14 @@ pub struct Camera {
 
     pub spp: u32,
     pub sspp: u32,
+    pub pixel_spread: f64, // angle subtended by a subpixel [rad]
+
+    pub wavelengths: WavelengthSampling, // traced, and the range of the spectral bins
+    pub bands: Vec<SpectralResponse>,    // of the band-integrated images
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         eye: &Eye,
         scene: &mut Scene,
@@ -99,16 +111,40 @@ impl Camera {
         sensor_w: f64,
         spp: u32,
         sspp: u32,
-    ) -> Self {
+    ) -> Result<Self> {
+        if pixel_num_w == 0 || pixel_num_h == 0 {
+            return Err(Error::InvalidArgument(
+                "image size must not be zero".to_string(),
+            ));
+        }
+        if spp == 0 || sspp == 0 {
+            return Err(Error::InvalidArgument(
+                "spp and sspp must not be zero".to_string(),
+            ));
+        }
+        if !(eye_to_sensor > 0. && sensor_w > 0.) {
+            return Err(Error::InvalidArgument(
+                "eye_to_sensor and sensor_w must be positive".to_string(),
+            ));
+        }
+        if !(-90. ..=90.).contains(&eye.elevation) || eye.altitude < 0. {
+            return Err(Error::InvalidArgument(
+                "elevation must be -90 - 90 and altitude must not be negative".to_string(),
+            ));
+        }
+        if 90. - eye.latitude.abs() < EPS {
+            return Err(Error::InvalidArgument(
+                "the eye can not be on the N/S pole".to_string(),
+            ));
+        }
         let eye_pos = eye.get_position(scene);
         let eye_dir = eye.get_direction(scene, &eye_pos);
         let sensor_h = sensor_w * pixel_num_h as f64 / pixel_num_w as f64;
 
         let sensor_u = if 90. - eye.elevation.abs() < EPS {
-            let axis = Vec3(0., to_radian(AXIS + 90.).cos(), to_radian(AXIS + 90.).sin());
-            cross(eye_dir, axis).normalize() * sensor_w
+            cross(eye_dir, earth_axis()).normalize() * sensor_w
         } else {
-            let up = (eye_pos - scene.earth.shape.center).normalize();
+            let up = scene.earth.shape.normal(&eye_pos);
 
             cross(eye_dir, up).normalize() * sensor_w
         };
@@ -117,8 +153,9 @@ impl Camera {
         let pixel_u = sensor_u / pixel_num_w as f64;
         let pixel_v = sensor_v / pixel_num_h as f64;
         let sensor_corner = eye_pos + eye_dir * eye_to_sensor - sensor_u / 2. - sensor_v / 2.;
+        let pixel_spread = pixel_u.length() / eye_to_sensor / sspp as f64;
 
-        Camera {
+        Ok(Camera {
             pixel_num_w,
             pixel_num_h,
             eye_pos,
@@ -127,7 +164,10 @@ impl Camera {
             pixel_v,
             spp,
             sspp,
-        }
+            pixel_spread,
+            wavelengths: WavelengthSampling::new(),
+            bands: Vec::new(),
+        })
     }
 
     pub fn get_pixel_center(&self, u: f64, v: f64, su: f64, sv: f64) -> Point3 {
@@ -135,4 +175,24 @@ impl Camera {
         let dv = self.pixel_v * (v + (sv + 0.5) / self.sspp as f64);
         self.sensor_corner + du + dv
     }
+
+    // reference of the Stokes vectors of a ray, along the horizontal of the image
+    pub fn polarization_frame(&self, dir: &Vec3) -> Vec3 {
+        orthogonalize(&self.pixel_u.normalize(), dir)
+    }
+}
+
+// spp is excluded so that a checkpoint can be resumed with more samples
+impl Hash for Camera {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.pixel_num_w.hash(state);
+        self.pixel_num_h.hash(state);
+        self.eye_pos.hash(state);
+        self.sensor_corner.hash(state);
+        self.pixel_u.hash(state);
+        self.pixel_v.hash(state);
+        self.sspp.hash(state);
+        self.wavelengths.hash(state);
+        self.bands.hash(state);
+    }
 }
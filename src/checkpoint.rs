@@ -0,0 +1,269 @@
+use std::{
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    time::Duration,
+};
+
+use crate::{camera::Camera, math::Vec3, random::XorRand, render::PixelState, scene::Scene};
+
+const MAGIC: &[u8; 8] = b"SKYCKPT4";
+
+pub struct Checkpoint {
+    pub path: String,
+    pub interval: Duration, // minimum time between two saves
+    pub batch: u32,         // spp added to each pixel per pass
+    pub resume: bool,       // continue from "path" if it exists
+}
+
+// FNV-1a, stable between runs unlike std's DefaultHasher
+struct Fnv(u64);
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+pub fn config_hash(scene: &Scene, camera: &Camera) -> u64 {
+    let mut hasher = Fnv(0xcbf29ce484222325);
+    scene.hash(&mut hasher);
+    camera.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub fn save(path: &str, hash: u64, buffer: &[PixelState]) -> io::Result<()> {
+    let tmp = format!("{path}.tmp");
+    {
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        w.write_all(MAGIC)?;
+        w.write_all(&hash.to_le_bytes())?;
+        w.write_all(&(buffer.len() as u64).to_le_bytes())?;
+        for p in buffer {
+            write_pixel(&mut w, p)?;
+        }
+        w.flush()?;
+    }
+    // a crash while writing never breaks the previous checkpoint
+    fs::rename(tmp, path)
+}
+
+pub fn load(path: &str, hash: u64, total_px: usize) -> io::Result<Vec<PixelState>> {
+    let mut r = BufReader::new(File::open(path)?);
+
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "not a checkpoint file",
+        ));
+    }
+    if read_u64(&mut r)? != hash {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "checkpoint was made with a different scene or camera",
+        ));
+    }
+    if read_u64(&mut r)? != total_px as u64 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "pixel count mismatch",
+        ));
+    }
+
+    (0..total_px).map(|_| read_pixel(&mut r)).collect()
+}
+
+pub fn write_pixel(w: &mut impl Write, p: &PixelState) -> io::Result<()> {
+    for f in [p.xyz.0, p.xyz.1, p.xyz.2, p.lum, p.lum_sq] {
+        w.write_all(&f.to_le_bytes())?;
+    }
+    w.write_all(&p.spp.to_le_bytes())?;
+    w.write_all(&p.rand.state().to_le_bytes())?;
+    w.write_all(&(p.spectrum.len() as u32).to_le_bytes())?;
+    for s in &p.spectrum {
+        w.write_all(&s.to_le_bytes())?;
+    }
+    w.write_all(&(p.bands.len() as u32).to_le_bytes())?;
+    for b in &p.bands {
+        w.write_all(&b.to_le_bytes())?;
+    }
+    w.write_all(&(p.stokes.len() as u32).to_le_bytes())?;
+    for s in &p.stokes {
+        w.write_all(&s.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub fn read_pixel(r: &mut impl Read) -> io::Result<PixelState> {
+    let xyz = Vec3(read_f64(r)?, read_f64(r)?, read_f64(r)?);
+    let lum = read_f64(r)?;
+    let lum_sq = read_f64(r)?;
+    let spp = read_u32(r)?;
+    let rand = XorRand::from_state(read_u32(r)?);
+    let bins = read_u32(r)?;
+    let spectrum = (0..bins).map(|_| read_f64(r)).collect::<io::Result<_>>()?;
+    let band_count = read_u32(r)?;
+    let bands = (0..band_count)
+        .map(|_| read_f64(r))
+        .collect::<io::Result<_>>()?;
+    let stokes_len = read_u32(r)?;
+    let stokes = (0..stokes_len)
+        .map(|_| read_f64(r))
+        .collect::<io::Result<_>>()?;
+    Ok(PixelState {
+        xyz,
+        lum,
+        lum_sq,
+        spp,
+        rand,
+        spectrum,
+        bands,
+        stokes,
+    })
+}
+
+pub fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+pub fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+pub fn read_f64(r: &mut impl Read) -> io::Result<f64> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b)?;
+    Ok(f64::from_le_bytes(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+
+    use crate::{
+        camera::{Direction, Eye},
+        progress::{Progress, ProgressReporter, Statistics},
+        render::{RenderConfig, render},
+        texture::Texture,
+    };
+
+    struct Resumed(Arc<AtomicBool>);
+
+    impl ProgressReporter for Resumed {
+        fn update(&self, _progress: &Progress) {}
+        fn finish(&self, _progress: &Progress, _stats: &Statistics) {}
+        fn resumed(&self, _path: &str) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(name)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn pixel(seed: u32) -> PixelState {
+        let mut rand = XorRand::new(seed);
+        rand.next01();
+        PixelState {
+            xyz: Vec3(0.1, 0.2, 0.3),
+            lum: 0.2,
+            lum_sq: 0.05,
+            spp: 7,
+            rand,
+            spectrum: vec![1., 2.],
+            bands: vec![3.],
+            stokes: vec![0.5, -0.5],
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = temp_path("sky-simulator-roundtrip.ckpt");
+        let buffer = vec![pixel(1), pixel(2)];
+        save(&path, 42, &buffer).unwrap();
+
+        let loaded = load(&path, 42, 2).unwrap();
+        for (a, b) in buffer.iter().zip(&loaded) {
+            assert_eq!(a.xyz.0.to_bits(), b.xyz.0.to_bits());
+            assert_eq!(a.xyz.2.to_bits(), b.xyz.2.to_bits());
+            assert_eq!(a.lum.to_bits(), b.lum.to_bits());
+            assert_eq!(a.lum_sq.to_bits(), b.lum_sq.to_bits());
+            assert_eq!(a.spp, b.spp);
+            assert_eq!(a.rand.state(), b.rand.state());
+            assert_eq!(a.spectrum, b.spectrum);
+            assert_eq!(a.bands, b.bands);
+            assert_eq!(a.stokes, b.stokes);
+        }
+    }
+
+    #[test]
+    fn reject_other_configs() {
+        let path = temp_path("sky-simulator-mismatch.ckpt");
+        save(&path, 42, &[pixel(1), pixel(2)]).unwrap();
+
+        for (hash, total_px) in [(43, 2), (42, 3)] {
+            let result = load(&path, hash, total_px);
+            assert!(matches!(result, Err(e) if e.kind() == ErrorKind::InvalidData));
+        }
+    }
+
+    // spp is left out of the hash, more samples continue the same image
+    #[test]
+    fn resume_with_more_samples() {
+        let texture = Texture::uniform(0.1);
+        let mut scene = Scene::new(3, &texture).unwrap();
+        let eye = Eye::new(12., 30., 1., Direction::South, 30.);
+        let few = Camera::new(&eye, &mut scene, 6, 4, 0.2, 0.5, 2, 1).unwrap();
+        let more = Camera::new(&eye, &mut scene, 6, 4, 0.2, 0.5, 4, 1).unwrap();
+        assert_eq!(config_hash(&scene, &few), config_hash(&scene, &more));
+        let finer = Camera::new(&eye, &mut scene, 6, 4, 0.2, 0.5, 4, 2).unwrap();
+        assert_ne!(config_hash(&scene, &few), config_hash(&scene, &finer));
+
+        let path = temp_path("sky-simulator-resume.ckpt");
+        let _ = fs::remove_file(&path);
+        let mut config = RenderConfig::new();
+        config.progress = None;
+        config.checkpoint = Some(Checkpoint {
+            path: path.clone(),
+            interval: Duration::from_secs(600),
+            batch: 1,
+            resume: true,
+        });
+        render(&scene, &few, &config).unwrap();
+        let loaded = load(&path, config_hash(&scene, &more), 24).unwrap();
+        assert!(loaded.iter().all(|p| p.spp == 2));
+
+        let was_resumed = Arc::new(AtomicBool::new(false));
+        config.progress = Some(Box::new(Resumed(was_resumed.clone())));
+        let resumed = render(&scene, &more, &config).unwrap();
+        assert!(was_resumed.load(Ordering::Relaxed));
+
+        config.checkpoint = None;
+        config.progress = None;
+        let direct = render(&scene, &more, &config).unwrap();
+        for (a, b) in resumed.xyz.iter().zip(&direct.xyz) {
+            assert_eq!(a.1.to_bits(), b.1.to_bits());
+        }
+        assert!(resumed.samples.iter().all(|s| s.spp == 4));
+    }
+}
@@ -0,0 +1,175 @@
+use std::hash::{Hash, Hasher};
+
+use crate::{
+    math::{Point3, Vec3, dot},
+    sphere::Earth,
+};
+
+// horizontal distribution of the clouds of a layer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloudCover {
+    Noise { scale: f64, seed: u64 }, // procedural, scale: size of the features [km]
+    Map,                             // cloud cover map of the texture
+}
+
+/*
+layer of water clouds between two altitudes
+
+the extinction follows from the liquid water content and the effective radius of the droplets
+in the limit of geometric optics
+
+G. L. Stephens. Radiation Profiles in Extended Water Clouds. II: Parameterization
+Schemes. In: Journal of the Atmospheric Sciences 35.11 (1978), pp. 2123–2132.
+*/
+pub struct CloudLayer {
+    pub base: f64,             // altitude [km]
+    pub top: f64,              // [km]
+    pub coverage: f64,         // 0 - 1
+    pub effective_radius: f64, // of the droplets [µm]
+    pub liquid_water: f64,     // content at the densest [g/m^3]
+    pub asymmetry: f64,        // g of the Henyey-Greenstein phase function
+    pub single_albedo: f64,
+    pub cover: CloudCover,
+}
+
+impl CloudLayer {
+    // stratocumulus
+    pub fn new(base: f64, top: f64, coverage: f64, cover: CloudCover) -> Self {
+        CloudLayer {
+            base,
+            top,
+            coverage,
+            effective_radius: 10.,
+            liquid_water: 0.3,
+            asymmetry: 0.85,
+            single_albedo: 0.9999,
+            cover,
+        }
+    }
+
+    // at the densest [1/km], 3 * LWC / (2 * density of water * effective radius)
+    pub fn extinction(&self) -> f64 {
+        1.5e3 * self.liquid_water / self.effective_radius
+    }
+
+    // 0 - 1, altitude of the point [km]
+    pub fn density(&self, earth: &Earth, point: &Point3, altitude: f64) -> f64 {
+        if altitude <= self.base || altitude >= self.top || self.coverage <= 0. {
+            return 0.;
+        }
+        // rounded at the base and the top
+        let x = (altitude - self.base) / (self.top - self.base);
+        let profile = 4. * x * (1. - x);
+
+        let cover = match self.cover {
+            CloudCover::Noise { scale, seed } => {
+                let p = *point - earth.shape.center;
+                let local = Vec3(dot(p, earth.u), dot(p, earth.v), dot(p, earth.w)) / scale;
+                // spread the values concentrated around the half
+                (0.5 + (fbm(local, seed) - 0.5) * 2.5).clamp(0., 1.)
+            }
+            CloudCover::Map => {
+                let (u, v) = earth.get_uv(point);
+                earth.texture.get_cloud_cover(u, v).unwrap_or(0.)
+            }
+        };
+
+        ((cover + self.coverage - 1.) / self.coverage).clamp(0., 1.) * profile
+    }
+}
+
+impl Hash for CloudLayer {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.base.to_bits().hash(state);
+        self.top.to_bits().hash(state);
+        self.coverage.to_bits().hash(state);
+        self.effective_radius.to_bits().hash(state);
+        self.liquid_water.to_bits().hash(state);
+        self.asymmetry.to_bits().hash(state);
+        self.single_albedo.to_bits().hash(state);
+        match self.cover {
+            CloudCover::Noise { scale, seed } => {
+                0.hash(state);
+                scale.to_bits().hash(state);
+                seed.hash(state);
+            }
+            CloudCover::Map => 1.hash(state),
+        }
+    }
+}
+
+/*
+thin shell draped with the cloud cover map of the texture, cheaper than a CloudLayer
+
+a ray crossing it passes through with the transmittance of the slab at the cover of the point,
+otherwise it is scattered there by the phase function
+*/
+pub struct CloudShell {
+    pub altitude: f64,      // [km]
+    pub optical_depth: f64, // vertical, under a full cover
+    pub asymmetry: f64,     // g of the Henyey-Greenstein phase function
+    pub single_albedo: f64,
+}
+
+impl CloudShell {
+    pub fn new(altitude: f64) -> Self {
+        CloudShell {
+            altitude,
+            optical_depth: 10.,
+            asymmetry: 0.85,
+            single_albedo: 0.9999,
+        }
+    }
+
+    // cover: 0 - 1, cos: between the ray and the normal
+    pub fn transmittance(&self, cover: f64, cos: f64) -> f64 {
+        (-self.optical_depth * cover / cos.abs().max(1e-3)).exp()
+    }
+}
+
+impl Hash for CloudShell {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.altitude.to_bits().hash(state);
+        self.optical_depth.to_bits().hash(state);
+        self.asymmetry.to_bits().hash(state);
+        self.single_albedo.to_bits().hash(state);
+    }
+}
+
+// 0 - 1
+fn lattice(x: i64, y: i64, z: i64, seed: u64) -> f64 {
+    let mut h = (x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        ^ (y as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f)
+        ^ (z as u64).wrapping_mul(0x1656_67b1_9e37_79f9)
+        ^ seed;
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// trilinear interpolation of random values on the integer lattice
+fn value_noise(p: Vec3, seed: u64) -> f64 {
+    let (x0, y0, z0) = (p.0.floor(), p.1.floor(), p.2.floor());
+    let fade = |t: f64| t * t * (3. - 2. * t);
+    let (fx, fy, fz) = (fade(p.0 - x0), fade(p.1 - y0), fade(p.2 - z0));
+    let (x0, y0, z0) = (x0 as i64, y0 as i64, z0 as i64);
+
+    let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+    let edge = |y: i64, z: i64| lerp(lattice(x0, y, z, seed), lattice(x0 + 1, y, z, seed), fx);
+    let face = |z: i64| lerp(edge(y0, z), edge(y0 + 1, z), fy);
+    lerp(face(z0), face(z0 + 1), fz)
+}
+
+// 4 octaves, 0 - 1
+fn fbm(p: Vec3, seed: u64) -> f64 {
+    let mut sum = 0.;
+    let mut amplitude = 0.5;
+    let mut p = p;
+    for octave in 0..4 {
+        sum += amplitude * value_noise(p, seed.wrapping_add(octave));
+        amplitude *= 0.5;
+        p = p * 2.;
+    }
+    sum / 0.9375
+}
@@ -1,11 +1,13 @@
-pub const PI: f64 = 3.14159265358979323846;
+pub const PI: f64 = std::f64::consts::PI;
 pub const PI_INV: f64 = 1. / PI;
 
 pub const INF: f64 = 1e100;
 pub const EPS: f64 = 1e-10;
-pub const E: f64 = 2.71828182846;
+pub const E: f64 = std::f64::consts::E;
 
 pub const EARTH_RAD: f64 = 6378.; // km
+pub const WGS84_A: f64 = 6378.137; // equatorial radius [km]
+pub const WGS84_F: f64 = 1. / 298.257_223_563; // flattening
 pub const AXIS: f64 = 23.4;
 pub const EARTH_TO_SUN: f64 = 1.5 * 1e8;
 pub const SUN_RAD: f64 = 6.96 * 1e5;
@@ -15,6 +17,5 @@ pub const SUN_LIGHT: f64 = 1.5 * 1e5;
 pub const NS: f64 = 2.54743 * 1e19;
 pub const PN: f64 = 0.0279; // depolarization factor
 
-pub const INT_XYZ: f64 = 106.76504616237932261548607350578
-    + 106.91973463815504343620398716858
-    + 106.82532490175775630470443615231;
+pub const INT_Y: f64 = 106.919_734_638_155_04;
+pub const INT_XYZ: f64 = 106.765_046_162_379_32 + INT_Y + 106.825_324_901_757_76;
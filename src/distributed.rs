@@ -0,0 +1,311 @@
+use std::{
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    camera::Camera,
+    checkpoint::{read_pixel, read_u32, read_u64, write_pixel},
+    error::{Error, Result},
+    progress::{ProgressReporter, Statistics, WorkerEvent},
+    render::{Job, PixelState, Rect},
+    scene::Scene,
+};
+
+const MAGIC: &[u8; 8] = b"SKYWRKR3";
+
+// for a peer to introduce itself, and for a worker to accept the answer
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Distributed {
+    pub addr: String,      // e.g. "0.0.0.0:7878"
+    pub workers: usize,    // the coordinator waits until this number of workers have connected
+    pub timeout: Duration, // longest wait for a worker, its tiles are handed out again after it
+}
+
+impl Distributed {
+    pub fn new(addr: &str, workers: usize) -> Self {
+        Distributed {
+            addr: addr.to_string(),
+            workers,
+            timeout: Duration::from_secs(600),
+        }
+    }
+}
+
+struct Worker {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    threads: u32,
+}
+
+impl Worker {
+    // None if the peer is not a worker of the same scene and camera
+    fn handshake(stream: TcpStream, hash: u64, timeout: Duration) -> io::Result<Option<Self>> {
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream.try_clone()?);
+
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        let accepted = &magic == MAGIC && read_u64(&mut reader)? == hash;
+        let threads = read_u32(&mut reader)?.max(1);
+        write_u32(&mut writer, accepted as u32)?;
+        writer.flush()?;
+        if !accepted {
+            return Ok(None);
+        }
+
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Some(Worker {
+            reader,
+            writer,
+            threads,
+        }))
+    }
+
+    // send tiles and receive the rendered pixels
+    fn run(&mut self, jobs: &[&Job]) -> io::Result<Vec<(Vec<PixelState>, Statistics)>> {
+        write_u32(&mut self.writer, jobs.len() as u32)?;
+        for job in jobs {
+            let Rect { x, y, w, h } = job.tile;
+            for n in [x, y, w, h] {
+                write_u32(&mut self.writer, n)?;
+            }
+            for (pixel, spp) in job.pixels.iter().zip(&job.spp) {
+                write_pixel(&mut self.writer, pixel)?;
+                write_u32(&mut self.writer, *spp)?;
+            }
+        }
+        self.writer.flush()?;
+
+        jobs.iter()
+            .map(|job| {
+                let pixels = (0..job.pixels.len())
+                    .map(|_| read_pixel(&mut self.reader))
+                    .collect::<io::Result<_>>()?;
+                let mut stats = [0; 7];
+                for s in &mut stats {
+                    *s = read_u64(&mut self.reader)?;
+                }
+                Ok((pixels, Statistics::from_array(stats)))
+            })
+            .collect()
+    }
+}
+
+pub struct WorkerPool {
+    workers: Vec<Worker>,
+}
+
+impl WorkerPool {
+    pub fn listen(
+        dist: &Distributed,
+        hash: u64,
+        progress: Option<&dyn ProgressReporter>,
+    ) -> io::Result<Self> {
+        let report = |event| {
+            if let Some(reporter) = progress {
+                reporter.worker_event(&event);
+            }
+        };
+        let listener = TcpListener::bind(&dist.addr)?;
+        report(WorkerEvent::Waiting {
+            addr: dist.addr.clone(),
+            workers: dist.workers,
+        });
+
+        // peers failing the handshake are dropped, the others still wait
+        let mut workers = vec![];
+        while workers.len() < dist.workers {
+            let (stream, peer) = listener.accept()?;
+            match Worker::handshake(stream, hash, dist.timeout) {
+                Ok(Some(worker)) => {
+                    report(WorkerEvent::Connected {
+                        peer,
+                        threads: worker.threads,
+                    });
+                    workers.push(worker);
+                }
+                Ok(None) => report(WorkerEvent::Rejected {
+                    peer,
+                    reason: "different scene or camera".to_string(),
+                }),
+                Err(e) => report(WorkerEvent::Rejected {
+                    peer,
+                    reason: e.to_string(),
+                }),
+            }
+        }
+
+        Ok(WorkerPool { workers })
+    }
+
+    // the results are in the order of jobs
+    pub fn run(
+        &mut self,
+        scene: &Scene,
+        camera: &Camera,
+        jobs: &[Job],
+        on_done: &(dyn Fn(&Job) + Sync),
+        progress: Option<&dyn ProgressReporter>,
+    ) -> Vec<(Vec<PixelState>, Statistics)> {
+        let queue = Mutex::new((0..jobs.len()).rev().collect::<Vec<usize>>());
+        let results = Mutex::new(vec![None; jobs.len()]);
+        let failed = Mutex::new(vec![]);
+
+        thread::scope(|s| {
+            for (id, worker) in self.workers.iter_mut().enumerate() {
+                let (queue, results, failed) = (&queue, &results, &failed);
+                s.spawn(move || {
+                    loop {
+                        let ids: Vec<usize> = {
+                            let mut queue = queue.lock().unwrap();
+                            let n = queue.len().saturating_sub(worker.threads as usize);
+                            queue.split_off(n)
+                        };
+                        if ids.is_empty() {
+                            break;
+                        }
+
+                        let batch: Vec<&Job> = ids.iter().map(|i| &jobs[*i]).collect();
+                        match worker.run(&batch) {
+                            Ok(pixels) => {
+                                let mut results = results.lock().unwrap();
+                                for (i, p) in ids.into_iter().zip(pixels) {
+                                    on_done(&jobs[i]);
+                                    results[i] = Some(p);
+                                }
+                            }
+                            Err(e) => {
+                                if let Some(reporter) = progress {
+                                    reporter.worker_event(&WorkerEvent::Failed {
+                                        id,
+                                        error: e.to_string(),
+                                    });
+                                }
+                                queue.lock().unwrap().extend(ids);
+                                failed.lock().unwrap().push(id);
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut failed = failed.into_inner().unwrap();
+        failed.sort();
+        for id in failed.into_iter().rev() {
+            self.workers.remove(id);
+        }
+
+        // tiles left by failed workers are rendered here
+        let mut results = results.into_inner().unwrap();
+        let left = queue.into_inner().unwrap();
+        if let Some(reporter) = progress
+            && !left.is_empty()
+        {
+            reporter.warning(&format!(
+                "rendering {} tiles of failed workers here",
+                left.len()
+            ));
+        }
+        let rendered: Vec<(usize, (Vec<PixelState>, Statistics))> = left
+            .into_par_iter()
+            .map(|i| {
+                let result = jobs[i].run(scene, camera);
+                on_done(&jobs[i]);
+                (i, result)
+            })
+            .collect();
+        for (i, pixels) in rendered {
+            results[i] = Some(pixels);
+        }
+
+        results.into_iter().map(|r| r.unwrap()).collect()
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        for worker in &mut self.workers {
+            let _ = write_u32(&mut worker.writer, 0).and_then(|_| worker.writer.flush());
+        }
+    }
+}
+
+// connect to a coordinator and render tiles until it has no more work
+pub fn work(addr: &str, scene: &Scene, camera: &Camera, hash: u64) -> Result<()> {
+    serve(addr, scene, camera, hash).map_err(Error::Network)
+}
+
+fn serve(addr: &str, scene: &Scene, camera: &Camera, hash: u64) -> io::Result<()> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream.try_clone()?);
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&hash.to_le_bytes())?;
+    write_u32(&mut writer, rayon::current_num_threads() as u32)?;
+    writer.flush()?;
+    if read_u32(&mut reader)? == 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "coordinator renders a different scene or camera",
+        ));
+    }
+    // the first tiles wait for the other workers to connect
+    stream.set_read_timeout(None)?;
+
+    loop {
+        let n = read_u32(&mut reader)?;
+        if n == 0 {
+            return Ok(());
+        }
+
+        let mut jobs = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            let tile = Rect::new(
+                read_u32(&mut reader)?,
+                read_u32(&mut reader)?,
+                read_u32(&mut reader)?,
+                read_u32(&mut reader)?,
+            );
+            let mut pixels = vec![];
+            let mut spp = vec![];
+            for _ in 0..tile.w * tile.h {
+                pixels.push(read_pixel(&mut reader)?);
+                spp.push(read_u32(&mut reader)?);
+            }
+            jobs.push(Job { tile, pixels, spp });
+        }
+
+        let results: Vec<(Vec<PixelState>, Statistics)> = jobs
+            .into_par_iter()
+            .map(|job| job.run(scene, camera))
+            .collect();
+        for (pixels, stats) in &results {
+            for pixel in pixels {
+                write_pixel(&mut writer, pixel)?;
+            }
+            for s in stats.to_array() {
+                writer.write_all(&s.to_le_bytes())?;
+            }
+        }
+        writer.flush()?;
+    }
+}
+
+fn write_u32(w: &mut impl Write, n: u32) -> io::Result<()> {
+    w.write_all(&n.to_le_bytes())
+}
@@ -0,0 +1,55 @@
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum Error {
+    Io { path: String, source: io::Error },
+    Decode { path: String, message: String },
+    Texture(String), // textures with inconsistent sizes or channels
+    InvalidArgument(String),
+    Checkpoint { path: String, message: String },
+    Network(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io(path: &str, source: io::Error) -> Self {
+        Error::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub fn decode(path: &str, message: impl fmt::Display) -> Self {
+        Error::Decode {
+            path: path.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Error::Io { source, .. } if source.kind() == io::ErrorKind::NotFound)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{path}: {source}"),
+            Error::Decode { path, message } => write!(f, "failed to decode {path}: {message}"),
+            Error::Texture(message) => write!(f, "invalid texture: {message}"),
+            Error::InvalidArgument(message) => write!(f, "invalid argument: {message}"),
+            Error::Checkpoint { path, message } => write!(f, "checkpoint {path}: {message}"),
+            Error::Network(source) => write!(f, "network error: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } | Error::Network(source) => Some(source),
+            _ => None,
+        }
+    }
+}
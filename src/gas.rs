@@ -0,0 +1,235 @@
+use std::hash::{Hash, Hasher};
+
+use crate::aerosol::Profile;
+
+// molecules per cm^2 of 1 cm of precipitable water
+const WATER_MOLECULES: f64 = 3.3428e22;
+
+// molecules per cm^2 of a Dobson unit
+const DOBSON_UNIT: f64 = 2.687e16;
+
+/*
+molecules absorbing in bands, Beer-Lambert with band-averaged cross-sections
+which lets too little light through the saturated lines
+*/
+pub struct AbsorbingGas {
+    pub column: f64, // vertical [molecules/cm^2]
+    pub profile: Profile,
+    pub cross_section: Vec<(f64, f64)>, // (wavelength [nm] ascending, [cm^2]), 0 outside
+}
+
+impl AbsorbingGas {
+    pub fn new(column: f64, profile: Profile, cross_section: Vec<(f64, f64)>) -> Self {
+        AbsorbingGas {
+            column,
+            profile,
+            cross_section,
+        }
+    }
+
+    // precipitable_water: [cm], 1 - 4 at the mid-latitudes
+    pub fn water_vapour(precipitable_water: f64) -> Self {
+        AbsorbingGas::new(
+            precipitable_water * WATER_MOLECULES,
+            Profile::Exponential { scale_height: 2. },
+            H2O_CROSS_SECTION.to_vec(),
+        )
+    }
+
+    // mixed with the air as the pressure falls
+    pub fn oxygen() -> Self {
+        AbsorbingGas::new(
+            4.5e24,
+            Profile::Exponential { scale_height: 8. },
+            O2_CROSS_SECTION.to_vec(),
+        )
+    }
+
+    // column: [molecules/cm^2], 1e15 clean - 1e17 polluted, in the boundary layer
+    pub fn nitrogen_dioxide(column: f64) -> Self {
+        AbsorbingGas::new(
+            column,
+            Profile::Exponential { scale_height: 1. },
+            NO2_CROSS_SECTION.to_vec(),
+        )
+    }
+
+    // column: [Dobson units], about 300, in the stratosphere
+    pub fn ozone(column: f64) -> Self {
+        AbsorbingGas::new(
+            column * DOBSON_UNIT,
+            Profile::Gaussian {
+                altitude: 22.,
+                width: 6.,
+            },
+            O3_CROSS_SECTION.to_vec(),
+        )
+    }
+
+    // interpolated linearly [cm^2]
+    pub fn cross_section(&self, wavelength: f64) -> f64 {
+        let table = &self.cross_section;
+        let i = table.partition_point(|(w, _)| *w < wavelength);
+        if i == 0 || i == table.len() {
+            return 0.;
+        }
+
+        let (w0, s0) = table[i - 1];
+        let (w1, s1) = table[i];
+        let t = (wavelength - w0) / (w1 - w0);
+        s0 * (1. - t) + s1 * t
+    }
+
+    // at the altitude [km] [1/km]
+    pub fn coeff(&self, h: f64, wavelength: f64) -> f64 {
+        self.density(wavelength) * self.profile.density(h)
+    }
+
+    // the largest between the altitudes [1/km]
+    pub fn max_coeff(&self, low: f64, high: f64, wavelength: f64) -> f64 {
+        self.density(wavelength) * self.profile.max_density(low, high)
+    }
+
+    // coefficient at the densest, the column spread over the thickness of the profile
+    fn density(&self, wavelength: f64) -> f64 {
+        self.cross_section(wavelength) * self.column / self.profile.thickness()
+    }
+}
+
+impl Hash for AbsorbingGas {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.column.to_bits().hash(state);
+        self.profile.hash(state);
+        for (w, s) in &self.cross_section {
+            w.to_bits().hash(state);
+            s.to_bits().hash(state);
+        }
+    }
+}
+
+/*
+rough averages over a few nm of the bands at 590 - 1870 nm, from their typical
+transmittances through a column of 2 cm of precipitable water
+*/
+const H2O_CROSS_SECTION: [(f64, f64); 36] = [
+    (585., 0.),
+    (592., 1.5e-25),
+    (598., 0.),
+    (640., 0.),
+    (651., 3.0e-25),
+    (660., 0.),
+    (695., 0.),
+    (718., 1.8e-24),
+    (725., 1.2e-24),
+    (735., 4.5e-25),
+    (745., 0.),
+    (805., 0.),
+    (815., 1.5e-24),
+    (823., 2.2e-24),
+    (835., 0.),
+    (890., 0.),
+    (905., 3.0e-24),
+    (935., 1.8e-23),
+    (950., 1.2e-23),
+    (970., 4.5e-24),
+    (990., 0.),
+    (1080., 0.),
+    (1110., 7.5e-24),
+    (1135., 2.2e-23),
+    (1160., 7.5e-24),
+    (1180., 0.),
+    (1300., 0.),
+    (1340., 3.0e-23),
+    (1380., 2.2e-22),
+    (1420., 7.5e-23),
+    (1480., 0.),
+    (1780., 0.),
+    (1820., 4.5e-23),
+    (1870., 2.2e-22),
+    (1920., 7.5e-23),
+    (2000., 0.),
+];
+
+// rough averages over about 1 nm of the gamma, B, A and 1270 nm bands
+const O2_CROSS_SECTION: [(f64, f64); 20] = [
+    (626., 0.),
+    (628., 6.7e-27),
+    (630., 2.2e-27),
+    (632., 0.),
+    (684., 0.),
+    (686.5, 5.6e-26),
+    (688., 3.3e-26),
+    (690., 1.1e-26),
+    (692., 0.),
+    (755., 0.),
+    (759., 1.3e-25),
+    (760.5, 3.3e-25),
+    (762., 2.2e-25),
+    (765., 8.9e-26),
+    (768., 3.3e-26),
+    (771., 0.),
+    (1262., 0.),
+    (1268., 6.7e-26),
+    (1272., 4.4e-26),
+    (1278., 0.),
+];
+
+/*
+approximate values, smoothed over the vibronic bands, of
+A. C. Vandaele et al. Measurements of the NO2 absorption cross-section from
+42000 cm-1 to 10000 cm-1 (238-1000 nm) at 220 K and 294 K. In: JQSRT 59 (1998),
+pp. 171-184.
+*/
+const NO2_CROSS_SECTION: [(f64, f64); 15] = [
+    (250., 1.0e-20),
+    (300., 1.5e-19),
+    (350., 4.5e-19),
+    (400., 5.8e-19),
+    (430., 5.5e-19),
+    (450., 5.0e-19),
+    (500., 3.0e-19),
+    (550., 1.5e-19),
+    (600., 6.0e-20),
+    (650., 2.5e-20),
+    (700., 1.0e-20),
+    (750., 3.0e-21),
+    (800., 1.0e-21),
+    (850., 3.0e-22),
+    (1000., 0.),
+];
+
+/*
+approximate values, smoothed over the bands of Hartley, Huggins and Chappuis, of
+J. Orphal. A critical review of the absorption cross-sections of O3 and NO2 in the
+ultraviolet and visible. In: J. Photochem. Photobiol. A 157 (2003), pp. 185–209.
+*/
+const O3_CROSS_SECTION: [(f64, f64); 28] = [
+    (200., 3.5e-19),
+    (220., 2.0e-18),
+    (240., 8.5e-18),
+    (255., 1.13e-17),
+    (270., 7.5e-18),
+    (280., 3.9e-18),
+    (290., 1.5e-18),
+    (300., 3.9e-19),
+    (310., 1.0e-19),
+    (320., 2.8e-20),
+    (330., 6.0e-21),
+    (340., 1.8e-21),
+    (350., 4.0e-22),
+    (360., 1.0e-22),
+    (380., 1.0e-23),
+    (400., 1.5e-23),
+    (450., 3.0e-22),
+    (500., 1.6e-21),
+    (550., 3.3e-21),
+    (575., 4.8e-21),
+    (600., 5.1e-21),
+    (625., 4.2e-21),
+    (650., 2.4e-21),
+    (700., 8.5e-22),
+    (750., 4.0e-22),
+    (800., 2.5e-22),
+    (900., 5.0e-23),
+    (1000., 0.),
+];
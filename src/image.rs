@@ -0,0 +1,195 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use bmp::{Image, Pixel, px};
+
+use crate::{
+    constant::PI,
+    polarization::linear,
+    progress::Statistics,
+    spectrum::{XYZ, convert_to_srgb, gamma},
+};
+
+pub struct Spectral {
+    pub bins: usize,
+    pub min: f64,       // [nm]
+    pub max: f64,       // [nm]
+    pub data: Vec<f64>, // spectral radiance of bins, pixel-interleaved
+}
+
+impl Spectral {
+    pub fn wavelength(&self, bin: usize) -> f64 {
+        self.min + (bin as f64 + 0.5) * (self.max - self.min) / self.bins as f64
+    }
+
+    pub fn get(&self, i: usize) -> &[f64] {
+        &self.data[i * self.bins..(i + 1) * self.bins]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SampleStats {
+    pub spp: u32,
+    pub mean_lum: f64,
+    pub relative_error: f64, // standard error of the mean luminance relative to the mean
+}
+
+pub struct RenderedImage {
+    pub width: u32,
+    pub height: u32,
+    pub xyz: Vec<XYZ>, // row-major
+    pub spectral: Option<Spectral>,
+    pub bands: Vec<Vec<f64>>, // band-integrated radiance of each response of the camera
+    pub linear_polarization: Option<Vec<(f64, f64)>>, // Q and U of the luminance
+    pub samples: Vec<SampleStats>,
+    pub stats: Statistics,
+}
+
+impl RenderedImage {
+    pub fn get(&self, u: u32, v: u32) -> XYZ {
+        self.xyz[(v * self.width + u) as usize]
+    }
+
+    // degree and angle [rad] of the linear polarization, the angle from the horizontal
+    // of the image toward the top
+    pub fn get_polarization(&self, u: u32, v: u32) -> Option<(f64, f64)> {
+        let (q, s) = self.linear_polarization.as_ref()?[(v * self.width + u) as usize];
+        Some(linear(self.get(u, v).1, q, s))
+    }
+}
+
+// sRGB with gamma
+pub fn write_bmp(image: &RenderedImage, path: &str) -> io::Result<()> {
+    let mut img = Image::new(image.width, image.height);
+    for (i, xyz) in image.xyz.iter().enumerate() {
+        let v = i as u32 / image.width;
+        let u = i as u32 - image.width * v;
+
+        let rgb = gamma(convert_to_srgb(xyz));
+        img.set_pixel(u, v, px!(rgb.0, rgb.1, rgb.2));
+    }
+    img.save(path)
+}
+
+// linear sRGB in 32-bit float
+pub fn write_pfm(image: &RenderedImage, path: &str) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write!(w, "PF\n{} {}\n-1.0\n", image.width, image.height)?;
+    // PFM stores rows from bottom to top
+    for v in (0..image.height).rev() {
+        for u in 0..image.width {
+            let rgb = convert_to_srgb(&image.get(u, v));
+            for c in [rgb.0, rgb.1, rgb.2] {
+                w.write_all(&(c as f32).to_le_bytes())?;
+            }
+        }
+    }
+    w.flush()
+}
+
+// band-integrated radiance of the band-th response of the camera in grayscale 32-bit float
+pub fn write_band_pfm(image: &RenderedImage, band: usize, path: &str) -> io::Result<()> {
+    let Some(data) = image.bands.get(band) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("image has no band {band}"),
+        ));
+    };
+
+    let mut w = BufWriter::new(File::create(path)?);
+    write!(w, "Pf\n{} {}\n-1.0\n", image.width, image.height)?;
+    for row in data.chunks(image.width as usize).rev() {
+        for value in row {
+            w.write_all(&(*value as f32).to_le_bytes())?;
+        }
+    }
+    w.flush()
+}
+
+// spp of each pixel in grayscale, normalized by the maximum
+pub fn write_sample_map(image: &RenderedImage, path: &str) -> io::Result<()> {
+    let mut img = Image::new(image.width, image.height);
+    let max_spp = image
+        .samples
+        .iter()
+        .map(|s| s.spp)
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    for (i, s) in image.samples.iter().enumerate() {
+        let v = i as u32 / image.width;
+        let u = i as u32 - image.width * v;
+        let c = (s.spp as f64 / max_spp as f64 * 255.) as u8;
+        img.set_pixel(u, v, px!(c, c, c));
+    }
+    img.save(path)
+}
+
+// ENVI image: "path" gets the raw 32-bit float cube and "path.hdr" the header
+pub fn write_spectral(image: &RenderedImage, path: &str) -> io::Result<()> {
+    let Some(spectral) = &image.spectral else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "image has no spectral data",
+        ));
+    };
+
+    let mut w = BufWriter::new(File::create(path)?);
+    for value in &spectral.data {
+        w.write_all(&(*value as f32).to_le_bytes())?;
+    }
+    w.flush()?;
+
+    let wavelengths: Vec<String> = (0..spectral.bins)
+        .map(|b| format!("{:.2}", spectral.wavelength(b)))
+        .collect();
+    let mut hdr = File::create(format!("{path}.hdr"))?;
+    write!(
+        hdr,
+        "ENVI\nsamples = {}\nlines = {}\nbands = {}\nheader offset = 0\nfile type = ENVI Standard\n\
+         data type = 4\ninterleave = bip\nbyte order = 0\nwavelength units = Nanometers\n\
+         wavelength = {{{}}}\n",
+        image.width,
+        image.height,
+        spectral.bins,
+        wavelengths.join(", ")
+    )
+}
+
+// degree of linear polarization in grayscale and the angle in hue, darkened where unpolarized
+pub fn write_polarization_bmp(
+    image: &RenderedImage,
+    dolp_path: &str,
+    aolp_path: &str,
+) -> io::Result<()> {
+    if image.linear_polarization.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "image has no polarization data",
+        ));
+    }
+
+    let mut dolp_img = Image::new(image.width, image.height);
+    let mut aolp_img = Image::new(image.width, image.height);
+    for v in 0..image.height {
+        for u in 0..image.width {
+            let (dolp, aolp) = image.get_polarization(u, v).unwrap_or((0., 0.));
+            let c = (dolp * 255.) as u8;
+            dolp_img.set_pixel(u, v, px!(c, c, c));
+
+            let rgb = hue(aolp / PI + 0.5);
+            let [r, g, b] = rgb.map(|c| (c * dolp * 255.) as u8);
+            aolp_img.set_pixel(u, v, px!(r, g, b));
+        }
+    }
+    dolp_img.save(dolp_path)?;
+    aolp_img.save(aolp_path)
+}
+
+// fully saturated color, h: 0 - 1
+fn hue(h: f64) -> [f64; 3] {
+    let ramp = |x: f64| ((x.rem_euclid(6.) - 3.).abs() - 1.).clamp(0., 1.);
+    [ramp(6. * h), ramp(6. * h - 2.), ramp(6. * h - 4.)]
+}
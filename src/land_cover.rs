@@ -0,0 +1,152 @@
+use std::{
+    fs,
+    hash::{Hash, Hasher},
+};
+
+use crate::error::{Error, Result};
+
+// classes of the land cover map, the value of a texel is the index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LandCover {
+    Water,
+    Forest,
+    Grassland,
+    Desert,
+    SnowIce,
+    Urban,
+}
+
+impl LandCover {
+    pub const ALL: [LandCover; 6] = [
+        LandCover::Water,
+        LandCover::Forest,
+        LandCover::Grassland,
+        LandCover::Desert,
+        LandCover::SnowIce,
+        LandCover::Urban,
+    ];
+
+    pub fn from_index(i: u8) -> Option<Self> {
+        Self::ALL.get(i as usize).copied()
+    }
+
+    // column name in the spectral library
+    pub fn name(&self) -> &'static str {
+        match self {
+            LandCover::Water => "water",
+            LandCover::Forest => "forest",
+            LandCover::Grassland => "grassland",
+            LandCover::Desert => "desert",
+            LandCover::SnowIce => "snow",
+            LandCover::Urban => "urban",
+        }
+    }
+}
+
+/*
+measured reflectance spectra of each class, e.g. from the ASTER/USGS spectral libraries
+
+# comment
+wavelength,water,forest,grassland,desert,snow,urban
+350,0.052,0.031,0.042,0.112,0.965,0.081
+...
+
+wavelengths are in nm (in µm if all of them are below 100) and reflectances are 0 - 1 (in % if any of them is above 1)
+*/
+pub struct SpectralLibrary {
+    wavelengths: Vec<f64>,      // ascending
+    reflectance: Vec<Vec<f64>>, // [class][wavelength]
+}
+
+impl SpectralLibrary {
+    pub fn load(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        let mut lines = text
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'));
+
+        let header: Vec<String> = lines
+            .next()
+            .ok_or_else(|| Error::decode(path, "no header"))?
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .collect();
+        let columns = LandCover::ALL
+            .iter()
+            .map(|c| {
+                header
+                    .iter()
+                    .position(|h| h == c.name())
+                    .ok_or_else(|| Error::decode(path, format!("no column \"{}\"", c.name())))
+            })
+            .collect::<Result<Vec<usize>>>()?;
+
+        let mut rows = Vec::new();
+        for (n, line) in lines.enumerate() {
+            let values = line
+                .split(',')
+                .map(|s| s.trim().parse::<f64>())
+                .collect::<std::result::Result<Vec<f64>, _>>()
+                .map_err(|e| Error::decode(path, format!("row {}: {e}", n + 1)))?;
+            if values.len() != header.len() {
+                return Err(Error::decode(
+                    path,
+                    format!(
+                        "row {}: {} columns, expected {}",
+                        n + 1,
+                        values.len(),
+                        header.len()
+                    ),
+                ));
+            }
+            rows.push(values);
+        }
+        if rows.is_empty() {
+            return Err(Error::decode(path, "no data"));
+        }
+        rows.sort_by(|a, b| a[0].total_cmp(&b[0]));
+
+        let mut wavelengths: Vec<f64> = rows.iter().map(|r| r[0]).collect();
+        if wavelengths.iter().all(|w| *w < 100.) {
+            wavelengths.iter_mut().for_each(|w| *w *= 1000.);
+        }
+        let percent = rows.iter().any(|r| columns.iter().any(|c| r[*c] > 1.));
+        let scale = if percent { 0.01 } else { 1. };
+        let reflectance = columns
+            .iter()
+            .map(|c| rows.iter().map(|r| (r[*c] * scale).clamp(0., 1.)).collect())
+            .collect();
+
+        Ok(SpectralLibrary {
+            wavelengths,
+            reflectance,
+        })
+    }
+
+    // linear interpolation, constant outside the measured range
+    pub fn reflectance(&self, class: LandCover, wavelength: f64) -> f64 {
+        let values = &self.reflectance[class as usize];
+        let i = self.wavelengths.partition_point(|w| *w < wavelength);
+        if i == 0 {
+            return values[0];
+        } else if i == self.wavelengths.len() {
+            return values[i - 1];
+        }
+
+        let (w0, w1) = (self.wavelengths[i - 1], self.wavelengths[i]);
+        let t = (wavelength - w0) / (w1 - w0);
+        values[i - 1] * (1. - t) + values[i] * t
+    }
+}
+
+impl Hash for SpectralLibrary {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for w in &self.wavelengths {
+            w.to_bits().hash(state);
+        }
+        for r in self.reflectance.iter().flatten() {
+            r.to_bits().hash(state);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+pub mod aerosol;
+pub mod bsdf;
+pub mod camera;
+pub mod checkpoint;
+pub mod cloud;
+pub mod constant;
+pub mod distributed;
+pub mod error;
+pub mod gas;
+pub mod image;
+pub mod land_cover;
+pub mod math;
+pub mod mie;
+pub mod ocean;
+pub mod pathtracing;
+pub mod polarization;
+pub mod progress;
+pub mod random;
+pub mod ray;
+pub mod render;
+pub mod sampling;
+pub mod scene;
+pub mod snow;
+pub mod spectrum;
+pub mod sphere;
+pub mod terrain;
+pub mod texture;
+
+pub use crate::{
+    camera::{Camera, Direction, Eye},
+    error::{Error, Result},
+    image::RenderedImage,
+    pathtracing::Pathtracing,
+    render::{RenderConfig, render},
+    scene::Scene,
+    sphere::Figure,
+    texture::{Filter, Texture, TexturePaths},
+};
@@ -1,57 +1,134 @@
-use crate::{
-    camera::{Camera, Direction, Eye},
-    render::render,
-    scene::Scene,
-    texture::Texture,
+use std::time::Duration;
+
+use sky_simulator::{
+    Camera, Direction, Error, Eye, Result, Scene, Texture,
+    checkpoint::{Checkpoint, config_hash},
+    distributed::{Distributed, work},
+    image::{RenderedImage, write_bmp, write_sample_map},
+    render::{Adaptive, Rect, RenderConfig, render},
+    texture::TexturePaths,
 };
 
-mod camera;
-mod constant;
-mod math;
-mod pathtracing;
-mod random;
-mod ray;
-mod render;
-mod sampling;
-mod scene;
-mod spectrum;
-mod sphere;
-mod texture;
+// the map of the samples per pixel is written only when asked for
+fn save(image: &RenderedImage, samples: bool) -> Result<()> {
+    write_bmp(image, "render.bmp").map_err(|e| Error::io("render.bmp", e))?;
+    if samples {
+        write_sample_map(image, "samples.bmp").map_err(|e| Error::io("samples.bmp", e))?;
+    }
+    Ok(())
+}
+
+// the Earth is rendered with a uniform albedo when there are no textures
+fn load_texture() -> Result<Texture> {
+    match Texture::load(&TexturePaths::solar_system_scope("assets")) {
+        Err(e) if e.is_not_found() => {
+            println!("{e}, using a uniform albedo");
+            Ok(Texture::uniform(0.1))
+        }
+        result => result,
+    }
+}
+
+/*
+sky-simulator                                 render on this machine
+sky-simulator coordinator <addr> <workers>    hand out tiles to workers
+sky-simulator worker <addr>                   render tiles for a coordinator
+--samples                                     also write samples.bmp
+*/
+fn run(scene: &Scene, camera: &Camera, mut config: RenderConfig) -> Result<()> {
+    let mut args: Vec<String> = std::env::args().collect();
+    let samples = args.iter().any(|a| a == "--samples");
+    args.retain(|a| a != "--samples");
+    match args.get(1).map(|s| s.as_str()) {
+        Some("coordinator") => {
+            let workers = match args.get(3) {
+                Some(n) => n
+                    .parse()
+                    .map_err(|_| Error::InvalidArgument(format!("invalid worker count {n}")))?,
+                None => 1,
+            };
+            let addr = args.get(2).map_or("127.0.0.1:7878", |s| s.as_str());
+            config.distributed = Some(Distributed::new(addr, workers));
+            save(&render(scene, camera, &config)?, samples)
+        }
+        Some("worker") => {
+            let addr = args.get(2).map_or("127.0.0.1:7878", |s| s.as_str());
+            work(addr, scene, camera, config_hash(scene, camera))
+        }
+        _ => save(&render(scene, camera, &config)?, samples),
+    }
+}
 
 #[allow(unused)]
-fn render_bluesky() {
-    let texture = Texture::new();
-    let mut scene = Scene::new(3, &texture);
+fn render_bluesky() -> Result<()> {
+    let texture = load_texture()?;
+    let mut scene = Scene::new(3, &texture)?;
     let eye = Eye::new(12., 30., 1., Direction::South, 30.);
-    let camera = Camera::new(&eye, &mut scene, 600, 400, 0.2, 0.5, 4, 4);
-    render(&scene, &camera);
+    let camera = Camera::new(&eye, &mut scene, 600, 400, 0.2, 0.5, 4, 4)?;
+    run(&scene, &camera, RenderConfig::new())
 }
 
 #[allow(unused)]
-fn render_sunset() {
-    let texture = Texture::new();
-    let mut scene = Scene::new(3, &texture);
+fn render_sunset() -> Result<()> {
+    let texture = load_texture()?;
+    let mut scene = Scene::new(3, &texture)?;
     let eye = Eye::new(18., 30., 1., Direction::West, 30.);
-    let camera = Camera::new(&eye, &mut scene, 600, 400, 0.2, 0.5, 4, 4);
-    render(&scene, &camera);
+    let camera = Camera::new(&eye, &mut scene, 600, 400, 0.2, 0.5, 4, 4)?;
+    let mut config = RenderConfig::new();
+    config.adaptive = Some(Adaptive {
+        max_spp: 64,
+        threshold: 0.02,
+        batch: 4,
+    });
+    run(&scene, &camera, config)
 }
 
 #[allow(unused)]
-fn render_earth() {
-    let texture = Texture::new();
-    let mut scene = Scene::new(3, &texture);
+fn render_earth() -> Result<()> {
+    let texture = load_texture()?;
+    let mut scene = Scene::new(3, &texture)?;
     let eye = Eye::new(12., 35., 6000., Direction::South, -90.);
-    let camera = Camera::new(&eye, &mut scene, 600, 400, 0.2, 0.5, 4, 4);
-    render(&scene, &camera);
+    let camera = Camera::new(&eye, &mut scene, 600, 400, 0.2, 0.5, 4, 4)?;
+    let mut config = RenderConfig::new();
+    config.adaptive = Some(Adaptive {
+        max_spp: 32,
+        threshold: 0.02,
+        batch: 4,
+    });
+    config.checkpoint = Some(Checkpoint {
+        path: "earth.ckpt".to_string(),
+        interval: Duration::from_secs(600),
+        batch: 1,
+        resume: true,
+    });
+    run(&scene, &camera, config)
 }
 
-fn main() {
-    let start = std::time::Instant::now();
+// re-render a part of the image of render_earth() with more samples
+#[allow(unused)]
+fn render_earth_region() -> Result<()> {
+    let texture = load_texture()?;
+    let mut scene = Scene::new(3, &texture)?;
+    let eye = Eye::new(12., 35., 6000., Direction::South, -90.);
+    let camera = Camera::new(&eye, &mut scene, 600, 400, 0.2, 0.5, 64, 4)?;
+    let mut config = RenderConfig::new();
+    config.checkpoint = Some(Checkpoint {
+        path: "earth.ckpt".to_string(),
+        interval: Duration::from_secs(600),
+        batch: 4,
+        resume: true,
+    });
+    config.region = Some(Rect::new(250, 150, 100, 100));
+    run(&scene, &camera, config)
+}
 
-    //render_bluesky();
-    //render_sunset();
-    render_earth();
+fn main() {
+    //let result = render_bluesky();
+    //let result = render_sunset();
+    let result = render_earth();
 
-    let end = start.elapsed();
-    println!("{}.{:03}sec", end.as_secs(), end.subsec_nanos() / 1000000);
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
 }
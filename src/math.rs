@@ -1,4 +1,7 @@
-use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::{
+    hash::{Hash, Hasher},
+    ops::{Add, Div, Mul, Neg, Sub},
+};
 
 use crate::constant::PI;
 
@@ -49,6 +52,14 @@ impl Div<f64> for Vec3 {
     }
 }
 
+impl Hash for Vec3 {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+        self.1.to_bits().hash(state);
+        self.2.to_bits().hash(state);
+    }
+}
+
 impl Vec3 {
     pub fn new(c: f64) -> Self {
         Vec3(c, c, c)
@@ -92,12 +103,7 @@ pub fn fmin(a: f64, b: f64) -> f64 {
 }
 
 pub fn is_valid(v: f64) -> bool {
-    if v.is_nan() {
-        return false;
-    } else if v < 0. {
-        return false;
-    }
-    true
+    v.is_finite() && v >= 0.
 }
 
 pub fn to_radian(angle: f64) -> f64 {
@@ -0,0 +1,313 @@
+use std::{
+    hash::{Hash, Hasher},
+    ops::{Add, Div, Mul, Sub},
+};
+
+use crate::constant::PI;
+
+// log-normal distribution of the number of particles over the radius
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LogNormal {
+    pub median_radius: f64, // [µm]
+    pub geometric_std: f64, // > 1
+}
+
+const TABLE_MIN: f64 = 250.; // [nm]
+const TABLE_MAX: f64 = 2500.; // [nm]
+const TABLE_STEP: f64 = 10.; // [nm]
+const ANGLE_BINS: usize = 720;
+const RADIUS_NODES: usize = 48;
+
+/*
+phase functions of a size distribution of spheres from the Lorenz-Mie theory,
+tabulated every 10 nm over 250nm - 2500nm to cover the absorption bands of the gases
+
+C. F. Bohren and D. R. Huffman. Absorption and Scattering of Light by
+Small Particles. Wiley, 1983. Appendix A.
+*/
+#[derive(Debug)]
+pub struct MieTables {
+    pub distribution: LogNormal,
+    pub ior: (f64, f64), // real part and absorption index of the particles
+    tables: Vec<PhaseTable>,
+}
+
+impl MieTables {
+    pub fn new(distribution: LogNormal, ior: (f64, f64)) -> Self {
+        let n = ((TABLE_MAX - TABLE_MIN) / TABLE_STEP) as usize + 1;
+        let tables = (0..n)
+            .map(|i| PhaseTable::new(&distribution, ior, TABLE_MIN + i as f64 * TABLE_STEP))
+            .collect();
+        MieTables {
+            distribution,
+            ior,
+            tables,
+        }
+    }
+
+    // of the nearest wavelength [nm]
+    pub fn get(&self, wavelength: f64) -> &PhaseTable {
+        let i = ((wavelength - TABLE_MIN) / TABLE_STEP).round().max(0.) as usize;
+        &self.tables[i.min(self.tables.len() - 1)]
+    }
+}
+
+impl Hash for MieTables {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.distribution.median_radius.to_bits().hash(state);
+        self.distribution.geometric_std.to_bits().hash(state);
+        self.ior.0.to_bits().hash(state);
+        self.ior.1.to_bits().hash(state);
+    }
+}
+
+/*
+phase function at one wavelength over bins of equal scattering angle,
+constant in the cosine within a bin so that it is sampled exactly
+*/
+#[derive(Debug)]
+pub struct PhaseTable {
+    pub asymmetry: f64,
+    phase: Vec<f64>,             // [1/sr]
+    cdf: Vec<f64>,               // up to the end of each bin
+    polarization: Vec<[f64; 3]>, // -S12, S33, S34 relative to S11
+}
+
+impl PhaseTable {
+    fn new(distribution: &LogNormal, ior: (f64, f64), wavelength: f64) -> Self {
+        let edges: Vec<f64> = (0..=ANGLE_BINS)
+            .map(|i| (i as f64 * PI / ANGLE_BINS as f64).cos())
+            .collect();
+
+        // S11, S12, S33, S34 summed over the radii, weighted by the number of particles
+        let mut sums = vec![[0.; 4]; edges.len()];
+        let ln_std = distribution.geometric_std.max(1. + 1e-6).ln();
+        let (ln_min, ln_max) = (-3. * ln_std, 5. * ln_std);
+        let k = 2. * PI / (wavelength * 1e-3); // [1/µm]
+        let m = Complex(ior.0, ior.1);
+        for node in 0..RADIUS_NODES {
+            let t = ln_min + (node as f64 + 0.5) * (ln_max - ln_min) / RADIUS_NODES as f64;
+            let weight = (-t * t / (2. * ln_std * ln_std)).exp();
+            let x = k * distribution.median_radius * t.exp();
+            for (sum, (s1, s2)) in sums.iter_mut().zip(amplitudes(x, m, &edges)) {
+                let s11 = 0.5 * (s1.norm_sq() + s2.norm_sq());
+                let s12 = 0.5 * (s2.norm_sq() - s1.norm_sq());
+                let s33 = (s2 * s1.conj()).0;
+                let s34 = (s2 * s1.conj()).1;
+                for (e, s) in sum.iter_mut().zip([s11, s12, s33, s34]) {
+                    *e += weight * s;
+                }
+            }
+        }
+
+        let masses: Vec<f64> = (0..ANGLE_BINS)
+            .map(|i| 0.5 * (sums[i][0] + sums[i + 1][0]) * (edges[i] - edges[i + 1]))
+            .collect();
+        let total: f64 = masses.iter().sum();
+
+        let mut cdf = Vec::with_capacity(ANGLE_BINS);
+        let mut acc = 0.;
+        let mut asymmetry = 0.;
+        for (i, mass) in masses.iter().enumerate() {
+            acc += mass / total;
+            cdf.push(acc);
+            asymmetry += mass / total * 0.5 * (edges[i] + edges[i + 1]);
+        }
+        let phase = (0..ANGLE_BINS)
+            .map(|i| masses[i] / total / (2. * PI * (edges[i] - edges[i + 1])))
+            .collect();
+        let polarization = (0..ANGLE_BINS)
+            .map(|i| {
+                let s = |j: usize| 0.5 * (sums[i][j] + sums[i + 1][j]);
+                [-s(1) / s(0), s(2) / s(0), s(3) / s(0)]
+            })
+            .collect();
+
+        PhaseTable {
+            asymmetry,
+            phase,
+            cdf,
+            polarization,
+        }
+    }
+
+    fn bin(cos_theta: f64) -> usize {
+        let theta = cos_theta.clamp(-1., 1.).acos();
+        ((theta / PI * ANGLE_BINS as f64) as usize).min(ANGLE_BINS - 1)
+    }
+
+    // cos_theta: cosine of the scattering angle [1/sr]
+    pub fn eval(&self, cos_theta: f64) -> f64 {
+        self.phase[PhaseTable::bin(cos_theta)]
+    }
+
+    // cosine of the scattering angle, u1, u2: 0 - 1
+    pub fn sample(&self, u1: f64, u2: f64) -> f64 {
+        let i = self.cdf.partition_point(|c| *c < u1).min(ANGLE_BINS - 1);
+        let cos0 = (i as f64 * PI / ANGLE_BINS as f64).cos();
+        let cos1 = ((i + 1) as f64 * PI / ANGLE_BINS as f64).cos();
+        cos0 + (cos1 - cos0) * u2
+    }
+
+    // -S12, S33, S34 relative to S11
+    pub fn polarization(&self, cos_theta: f64) -> [f64; 3] {
+        self.polarization[PhaseTable::bin(cos_theta)]
+    }
+}
+
+// S1, S2 of a sphere of size parameter x and relative refractive index m at the cosines mu
+fn amplitudes(x: f64, m: Complex, mu: &[f64]) -> Vec<(Complex, Complex)> {
+    let n_stop = (x + 4. * x.cbrt() + 2.) as usize;
+    let y = m * x;
+    let n_max = n_stop.max(y.norm_sq().sqrt() as usize) + 15;
+
+    // logarithmic derivative by downward recurrence
+    let mut d = vec![Complex(0., 0.); n_max + 1];
+    for n in (1..=n_max).rev() {
+        let n_y = Complex(n as f64, 0.) / y;
+        d[n - 1] = n_y - Complex(1., 0.) / (d[n] + n_y);
+    }
+
+    let mut s = vec![(Complex(0., 0.), Complex(0., 0.)); mu.len()];
+    let mut pi0 = vec![0.; mu.len()];
+    let mut pi1 = vec![1.; mu.len()];
+    let (mut psi0, mut psi1) = (x.cos(), x.sin());
+    let (mut chi0, mut chi1) = (-x.sin(), x.cos());
+    for (n, d) in d.iter().enumerate().take(n_stop + 1).skip(1) {
+        let nf = n as f64;
+        let psi = (2. * nf - 1.) / x * psi1 - psi0;
+        let chi = (2. * nf - 1.) / x * chi1 - chi0;
+        let xi = Complex(psi, -chi);
+        let xi1 = Complex(psi1, -chi1);
+
+        let da = *d / m + Complex(nf / x, 0.);
+        let db = m * *d + Complex(nf / x, 0.);
+        let a = (da * psi - Complex(psi1, 0.)) / (da * xi - xi1);
+        let b = (db * psi - Complex(psi1, 0.)) / (db * xi - xi1);
+
+        let f = (2. * nf + 1.) / (nf * (nf + 1.));
+        for (j, mu) in mu.iter().enumerate() {
+            let (pi, pi_prev) = (pi1[j], pi0[j]);
+            let tau = nf * mu * pi - (nf + 1.) * pi_prev;
+            s[j].0 = s[j].0 + (a * pi + b * tau) * f;
+            s[j].1 = s[j].1 + (a * tau + b * pi) * f;
+            pi0[j] = pi;
+            pi1[j] = ((2. * nf + 1.) * mu * pi - (nf + 1.) * pi_prev) / nf;
+        }
+
+        (psi0, psi1) = (psi1, psi);
+        (chi0, chi1) = (chi1, chi);
+    }
+    s
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Complex(f64, f64);
+
+impl Add for Complex {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Complex(self.0 + rhs.0, self.1 + rhs.1)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self::Output {
+        Complex(self.0 - rhs.0, self.1 - rhs.1)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self::Output {
+        Complex(
+            self.0 * rhs.0 - self.1 * rhs.1,
+            self.0 * rhs.1 + self.1 * rhs.0,
+        )
+    }
+}
+
+impl Mul<f64> for Complex {
+    type Output = Self;
+    fn mul(self, rhs: f64) -> Self::Output {
+        Complex(self.0 * rhs, self.1 * rhs)
+    }
+}
+
+impl Div for Complex {
+    type Output = Self;
+    fn div(self, rhs: Self) -> Self::Output {
+        let n = rhs.norm_sq();
+        Complex(
+            (self.0 * rhs.0 + self.1 * rhs.1) / n,
+            (self.1 * rhs.0 - self.0 * rhs.1) / n,
+        )
+    }
+}
+
+impl Complex {
+    fn conj(self) -> Self {
+        Complex(self.0, -self.1)
+    }
+
+    fn norm_sq(self) -> f64 {
+        self.0 * self.0 + self.1 * self.1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::XorRand;
+
+    // nearly a single radius, of size parameter x at 500nm
+    fn single(x: f64, ior: (f64, f64)) -> PhaseTable {
+        let distribution = LogNormal {
+            median_radius: x * 0.5 / (2. * PI),
+            geometric_std: 1.,
+        };
+        PhaseTable::new(&distribution, ior, 500.)
+    }
+
+    fn solid_angle(i: usize) -> f64 {
+        let cos = |i: usize| (i as f64 * PI / ANGLE_BINS as f64).cos();
+        2. * PI * (cos(i) - cos(i + 1))
+    }
+
+    #[test]
+    fn phase_is_normalized() {
+        for table in [single(1., (1.33, 0.)), single(10., (1.5, 0.01))] {
+            let integral: f64 = (0..ANGLE_BINS)
+                .map(|i| table.phase[i] * solid_angle(i))
+                .sum();
+            assert!((integral - 1.).abs() < 1e-9);
+            assert!((table.cdf[ANGLE_BINS - 1] - 1.).abs() < 1e-9);
+        }
+    }
+
+    // 0.1845 from the series of the Mie coefficients, Bohren and Huffman (4.74)
+    #[test]
+    fn asymmetry_of_a_water_droplet() {
+        let table = single(1., (1.33, 0.));
+        assert!((table.asymmetry - 0.1845).abs() < 1e-4);
+    }
+
+    #[test]
+    fn samples_follow_the_table() {
+        let table = single(5., (1.33, 0.));
+        for k in 1..1000 {
+            let u = k as f64 / 1000.;
+            let i = PhaseTable::bin(table.sample(u, 0.5));
+            assert!(table.cdf[i] >= u && (i == 0 || table.cdf[i - 1] < u));
+        }
+
+        let mut rand = XorRand::new(3);
+        let n = 100000;
+        let mean: f64 = (0..n)
+            .map(|_| table.sample(rand.next01(), rand.next01()))
+            .sum::<f64>()
+            / n as f64;
+        assert!((mean - table.asymmetry).abs() < 0.01);
+    }
+}
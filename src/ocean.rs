@@ -0,0 +1,103 @@
+use std::hash::{Hash, Hasher};
+
+use crate::{
+    bsdf::{Bsdf, Microfacet},
+    constant::PI_INV,
+    math::{Vec3, dot},
+    polarization::Mueller,
+    random::XorRand,
+    sampling::{pdf_sample_cos_hemi, sample_cos_hemisphere},
+};
+
+/*
+sea surface: Fresnel reflection on wind-roughened facets and light leaving the water body
+
+C. Cox and W. Munk. Measurement of the Roughness of the Sea Surface from
+Photographs of the Sun's Glitter. In: JOSA 44.11 (1954), pp. 838–850.
+*/
+pub struct Ocean {
+    pub wind_speed: f64, // [m/s] 12.5 m above the sea
+}
+
+impl Ocean {
+    pub fn new(wind_speed: f64) -> Self {
+        Ocean { wind_speed }
+    }
+
+    // water: reflectance of the water body
+    pub fn surface(&self, water: f64, wavelength: f64) -> OceanSurface {
+        // mean square slope of the facets
+        let slope_variance = 0.003 + 0.00512 * self.wind_speed.max(0.);
+        OceanSurface {
+            glint: Microfacet::dielectric(slope_variance.sqrt(), water_ior(wavelength)),
+            water,
+        }
+    }
+}
+
+impl Hash for Ocean {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.wind_speed.to_bits().hash(state);
+    }
+}
+
+pub struct OceanSurface {
+    glint: Microfacet,
+    water: f64,
+}
+
+impl OceanSurface {
+    // probability to sample the glint instead of the water-leaving light
+    fn glint_prob(&self, normal: &Vec3, wo: &Vec3) -> f64 {
+        let f = self.glint.fresnel(dot(*normal, *wo));
+        let body = (1. - f) * self.water;
+        if f + body > 0. { f / (f + body) } else { 1. }
+    }
+}
+
+impl Bsdf for OceanSurface {
+    fn eval(&self, normal: &Vec3, wo: &Vec3, wi: &Vec3) -> f64 {
+        let cos_o = dot(*normal, *wo);
+        let cos_i = dot(*normal, *wi);
+        if cos_o <= 0. || cos_i <= 0. {
+            return 0.;
+        }
+
+        // light refracted into the water, scattered and refracted out again
+        let body = (1. - self.glint.fresnel(cos_i)) * (1. - self.glint.fresnel(cos_o)) * self.water;
+
+        self.glint.eval(normal, wo, wi) + body * PI_INV
+    }
+
+    fn sample(&self, normal: &Vec3, wo: &Vec3, rand: &mut XorRand) -> Option<Vec3> {
+        if rand.next01() < self.glint_prob(normal, wo) {
+            self.glint.sample(normal, wo, rand)
+        } else {
+            Some(sample_cos_hemisphere(normal, rand))
+        }
+    }
+
+    fn pdf(&self, normal: &Vec3, wo: &Vec3, wi: &Vec3) -> f64 {
+        if dot(*normal, *wo) <= 0. || dot(*normal, *wi) <= 0. {
+            return 0.;
+        }
+        let p = self.glint_prob(normal, wo);
+        p * self.glint.pdf(normal, wo, wi) + (1. - p) * pdf_sample_cos_hemi(normal, wi)
+    }
+
+    // polarized by the glint, the water-leaving light is unpolarized
+    fn mueller(&self, normal: &Vec3, wo: &Vec3, wi: &Vec3) -> Mueller {
+        let total = self.eval(normal, wo, wi);
+        if total <= 0. {
+            return Mueller::depolarizer();
+        }
+        let glint = self.glint.eval(normal, wo, wi) / total;
+        self.glint.mueller(normal, wo, wi) * glint + Mueller::depolarizer() * (1. - glint)
+    }
+}
+
+// fit to the refractive index of sea water, wavelength in nm
+fn water_ior(wavelength: f64) -> f64 {
+    let w2 = wavelength * wavelength;
+    1.3247 + 3.3e3 / w2 - 3.2e7 / (w2 * w2)
+}
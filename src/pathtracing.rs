@@ -1,12 +1,12 @@
 use crate::{
-    constant::{EARTH_RAD, KARMAN_LINE, PI_INV, SUN_LIGHT},
+    bsdf::Bsdf,
+    constant::SUN_LIGHT,
     math::{Vec3, dot, fmax},
+    polarization::{Mueller, Stokes, orthogonalize, scattering_frame},
+    progress::Statistics,
     random::XorRand,
     ray::{HitRecord, Ray},
-    sampling::{
-        ScatteringType, pdf_phase, pdf_sample_cos_hemi, reflection_dir, sample_cos_hemisphere,
-        sample_phase,
-    },
+    sampling::{ScatteringType, mueller_phase, pdf_phase, sample_phase},
     scene::Scene,
     sphere::ObjectType,
 };
@@ -14,6 +14,13 @@ use crate::{
 const DEPTH: u32 = 6;
 const MAX_DEPTH: u32 = 30;
 
+// Stokes vectors carried along the path
+struct Polarization {
+    mueller: Mueller, // from the light arriving along now_ray to the camera, relative to throughput
+    frame: Vec3,      // reference of the light arriving along now_ray
+    stokes: Stokes,   // at the camera
+}
+
 pub struct Pathtracing {
     wavelength: f64,
     now_ray: Ray,
@@ -24,10 +31,15 @@ pub struct Pathtracing {
     total_pdf: f64,
     pdf_sample_pt: f64,
     value: f64,
+    cone_spread: f64, // [rad], 0 after a diffuse bounce or a scattering
+    cone_width: f64,  // [km]
+    polarization: Option<Polarization>,
+    pub stats: Statistics,
 }
 
 impl Pathtracing {
-    pub fn new(ray: Ray, wavelength: f64) -> Self {
+    // spread: angle of the ray cone used to filter textures
+    pub fn new(ray: Ray, wavelength: f64, spread: f64) -> Self {
         Pathtracing {
             wavelength,
             now_ray: ray,
@@ -38,6 +50,57 @@ impl Pathtracing {
             total_pdf: 1.,
             pdf_sample_pt: -1.,
             value: 0.,
+            cone_spread: spread,
+            cone_width: 0.,
+            polarization: None,
+            stats: Statistics::default(),
+        }
+    }
+
+    // tracks the Stokes vectors, frame: reference at the camera, perpendicular to the ray
+    pub fn set_polarization_frame(&mut self, frame: Vec3) {
+        self.polarization = Some(Polarization {
+            mueller: Mueller::identity(),
+            frame,
+            stokes: [0.; 4],
+        });
+    }
+
+    // after integrate(), None if the polarization is not tracked
+    pub fn stokes(&self) -> Option<Stokes> {
+        self.polarization.as_ref().map(|p| p.stokes)
+    }
+
+    // unpolarized light arriving along now_ray
+    fn add_light(&mut self, value: f64) {
+        self.value += value;
+        if let Some(p) = &mut self.polarization {
+            for (s, c) in p.stokes.iter_mut().zip(p.mueller.column(0)) {
+                *s += value * c;
+            }
+        }
+    }
+
+    // unpolarized light from wi turned back along now_ray, local: relative Mueller matrix
+    fn add_scattered_light(&mut self, value: f64, wi: &Vec3, local: impl FnOnce() -> Mueller) {
+        self.value += value;
+        let wo = -self.now_ray.dir;
+        if let Some(p) = &mut self.polarization {
+            let x = scattering_frame(&wo, wi, &p.frame);
+            let m = p.mueller * Mueller::rotation(&x, &p.frame, &wo) * local();
+            for (s, c) in p.stokes.iter_mut().zip(m.column(0)) {
+                *s += value * c;
+            }
+        }
+    }
+
+    // the path goes on to wi, before now_ray is replaced
+    fn turn_polarization(&mut self, wi: &Vec3, local: impl FnOnce() -> Mueller) {
+        let wo = -self.now_ray.dir;
+        if let Some(p) = &mut self.polarization {
+            let x = scattering_frame(&wo, wi, &p.frame);
+            p.mueller = p.mueller * Mueller::rotation(&x, &p.frame, &wo) * local();
+            p.frame = x;
         }
     }
 
@@ -58,81 +121,94 @@ impl Pathtracing {
         }
     }
 
-    fn trace_earth(&mut self, scene: &Scene, rand: &mut XorRand) {
-        // lambertian model
-        let new_dir = sample_cos_hemisphere(&self.texture_normal, rand);
-        let new_org = self.record.hitpoint + 0.00001 * self.obj_normal;
-        self.now_ray = Ray::new(new_org, new_dir);
-        self.throughput *= scene
-            .earth
-            .get_reflectance(&self.record.hitpoint, self.wavelength);
-
-        let coeff_rayleigh = scene.scattering_coeff_rayleigh(&new_org, self.wavelength);
-        let coeff_mie = scene.coeff_mie(&new_org);
-        let sc_type =
-            if rand.next01() < coeff_rayleigh / (coeff_rayleigh + coeff_mie.0 + coeff_mie.1) {
-                ScatteringType::Rayleigh
-            } else {
-                ScatteringType::Mie
-            };
+    // false if the path ends
+    fn trace_earth(&mut self, scene: &Scene, bsdf: &dyn Bsdf, rand: &mut XorRand) -> bool {
+        let normal = self.texture_normal;
+        let wo = -self.now_ray.dir;
+        let new_org = self.record.hitpoint + self.obj_normal * 0.00001;
+        self.cone_spread = 0.;
+        self.cone_width = 0.;
 
-        let nee_result = scene.nee(&new_org, self.wavelength, &sc_type, rand);
+        let nee_result = scene.nee(&new_org, self.wavelength, rand, &mut self.stats);
         if nee_result.pdf != 0. {
-            let pdf_pt = pdf_sample_cos_hemi(&self.texture_normal, &nee_result.dir);
-            let cosine = fmax(dot(self.texture_normal, nee_result.dir), 0.);
+            let value = bsdf.eval(&normal, &wo, &nee_result.dir);
+            let pdf_pt = bsdf.pdf(&normal, &wo, &nee_result.dir);
+            let cosine = fmax(dot(normal, nee_result.dir), 0.);
             let mis_weight = 1. / (pdf_pt + nee_result.pdf);
             //transmittance=1
-            self.value +=
-                self.throughput * nee_result.value * PI_INV * cosine * mis_weight / self.total_pdf;
+            self.add_scattered_light(
+                self.throughput * nee_result.value * value * cosine * mis_weight / self.total_pdf,
+                &nee_result.dir,
+                || bsdf.mueller(&normal, &wo, &nee_result.dir),
+            );
         }
 
-        self.pdf_sample_pt = pdf_sample_cos_hemi(&self.texture_normal, &new_dir);
-    }
-
-    fn trace_earth_specular(&mut self, scene: &Scene) {
-        self.throughput *= scene
-            .earth
-            .get_reflectance(&self.record.hitpoint, self.wavelength);
+        let Some(new_dir) = bsdf.sample(&normal, &wo, rand) else {
+            return false;
+        };
+        let pdf = bsdf.pdf(&normal, &wo, &new_dir);
+        if pdf == 0. || dot(new_dir, self.obj_normal) <= 0. {
+            return false;
+        }
 
-        let new_dir = reflection_dir(&self.now_ray.dir, &self.texture_normal);
-        let new_org = self.record.hitpoint + self.obj_normal * 0.00001;
+        self.throughput *= bsdf.eval(&normal, &wo, &new_dir) * dot(normal, new_dir) / pdf;
+        self.turn_polarization(&new_dir, || bsdf.mueller(&normal, &wo, &new_dir));
         self.now_ray = Ray::new(new_org, new_dir);
-        self.pdf_sample_pt = 1.;
+        self.pdf_sample_pt = pdf;
+        true
     }
 
-    fn freepath_sample(&mut self, scene: &Scene, rand: &mut XorRand) -> bool {
-        let coeff_rayleigh = scene.scattering_coeff_rayleigh(&self.now_ray.org, self.wavelength);
-        let coeff_mie = scene.coeff_mie(&self.now_ray.org);
-
-        let single_albedo;
-        let sc_type;
-        if rand.next01() < coeff_rayleigh / (coeff_rayleigh + coeff_mie.0 + coeff_mie.1) {
-            single_albedo = 1.;
-            sc_type = ScatteringType::Rayleigh;
-        } else {
-            single_albedo = coeff_mie.0 / (coeff_mie.0 + coeff_mie.1);
-            sc_type = ScatteringType::Mie;
+    // by the phase function at a point of a medium or the cloud shell
+    fn scatter(
+        &mut self,
+        scene: &Scene,
+        point: &Vec3,
+        sc_type: &ScatteringType,
+        single_albedo: f64,
+        rand: &mut XorRand,
+    ) {
+        let (new_dir, pdf_phase_pt) = sample_phase(sc_type, &-self.now_ray.dir, rand);
+        self.throughput *= single_albedo;
+        if single_albedo == 0. {
+            return;
         }
 
-        let tracking_result = scene.delta_tracking(&self.now_ray, self.wavelength, &sc_type, rand);
-        if let (None, point) = tracking_result {
-            let (new_dir, pdf_phase_pt) = sample_phase(&sc_type, &-self.now_ray.dir, rand);
-            self.throughput *= single_albedo;
-
-            let nee_result = scene.nee(&point, self.wavelength, &sc_type, rand);
-            if nee_result.pdf != 0. {
-                let pdf_phase_nee = pdf_phase(&sc_type, &nee_result.dir, &-self.now_ray.dir);
-                let mis_weight = 1. / (pdf_phase_nee + nee_result.pdf);
-                self.value += self.throughput * nee_result.value * pdf_phase_nee * mis_weight
-                    / self.total_pdf;
-            }
+        let nee_result = scene.nee(point, self.wavelength, rand, &mut self.stats);
+        if nee_result.pdf != 0. {
+            let wo = -self.now_ray.dir;
+            let pdf_phase_nee = pdf_phase(sc_type, &nee_result.dir, &wo);
+            let mis_weight = 1. / (pdf_phase_nee + nee_result.pdf);
+            self.add_scattered_light(
+                self.throughput * nee_result.value * pdf_phase_nee * mis_weight / self.total_pdf,
+                &nee_result.dir,
+                || mueller_phase(sc_type, &nee_result.dir, &wo),
+            );
+        }
 
-            self.now_ray = Ray::new(point, new_dir);
-            self.pdf_sample_pt = pdf_phase_pt;
-            return false;
-        } else if let (Some(record), _) = tracking_result {
-            self.record = record;
+        let wo = -self.now_ray.dir;
+        self.turn_polarization(&new_dir, || mueller_phase(sc_type, &new_dir, &wo));
+        self.now_ray = Ray::new(*point, new_dir);
+        self.pdf_sample_pt = pdf_phase_pt;
+        self.cone_spread = 0.;
+        self.cone_width = 0.;
+    }
+
+    fn freepath_sample(&mut self, scene: &Scene, rand: &mut XorRand) -> bool {
+        // the ray is bent by the refraction
+        let (record, ray) =
+            scene.delta_tracking(&self.now_ray, self.wavelength, rand, &mut self.stats);
+        if let Some(p) = &mut self.polarization {
+            p.frame = orthogonalize(&p.frame, &ray.dir);
         }
+        let Some(record) = record else {
+            let point = ray.org;
+            self.now_ray = ray;
+            let (sc_type, single_albedo) = scene.sample_medium(&point, self.wavelength, rand);
+            self.scatter(scene, &point, &sc_type, single_albedo, rand);
+            return false;
+        };
+        self.record = record;
+        self.now_ray.dir = ray.dir;
 
         // hit to obj
         true
@@ -140,13 +216,19 @@ impl Pathtracing {
 
     pub fn integrate(&mut self, scene: &Scene, rand: &mut XorRand) -> f64 {
         let mut in_atmosphere = scene.in_atmosphere(&self.now_ray.org);
+        self.stats.paths += 1;
         for time in 0.. {
             if !self.roullete(time, rand) {
                 break;
             }
+            self.stats.depth += 1;
 
             if in_atmosphere {
                 if !self.freepath_sample(scene, rand) {
+                    // absorbed by a gas
+                    if self.throughput == 0. {
+                        break;
+                    }
                     continue;
                 }
             } else {
@@ -156,6 +238,8 @@ impl Pathtracing {
                 }
             }
 
+            self.cone_width += self.cone_spread * self.record.distance;
+
             self.obj_normal = if dot(self.now_ray.dir, self.record.normal) < 0. {
                 self.record.normal
             } else {
@@ -165,17 +249,20 @@ impl Pathtracing {
             match self.record.obj_type {
                 ObjectType::Sun => {
                     if self.pdf_sample_pt < 0. {
-                        self.value += self.throughput * SUN_LIGHT / self.total_pdf;
+                        self.add_light(self.throughput * SUN_LIGHT / self.total_pdf);
                     } else {
                         let pdf_nee = scene.sun.pdf_sampling(&self.now_ray.org);
                         let mis_weight = self.pdf_sample_pt / (self.pdf_sample_pt + pdf_nee);
-                        self.value += self.throughput * SUN_LIGHT * mis_weight / self.total_pdf;
+                        self.add_light(self.throughput * SUN_LIGHT * mis_weight / self.total_pdf);
                     }
                     break;
                 }
                 ObjectType::Earth => {
                     let (u, v) = scene.earth.get_uv(&self.record.hitpoint);
-                    let (specular, normal) = scene.earth.get_property(&self.record.hitpoint, u, v);
+                    let (specular, normal) =
+                        scene
+                            .earth
+                            .get_property(&self.record.normal, u, v, self.cone_width);
 
                     self.texture_normal = if dot(self.now_ray.dir, normal) < 0. {
                         normal
@@ -183,29 +270,46 @@ impl Pathtracing {
                         -normal
                     };
 
-                    if specular < 120 {
-                        self.trace_earth(scene, rand);
-                    } else {
-                        self.trace_earth_specular(scene);
+                    let bsdf = scene.earth.get_material(
+                        u,
+                        v,
+                        specular,
+                        self.wavelength,
+                        self.cone_width,
+                        &scene.ocean,
+                    );
+                    if !self.trace_earth(scene, bsdf.as_ref(), rand) {
+                        break;
                     }
                 }
+                // scattering, the gaps are passed through in delta_tracking()
+                ObjectType::CloudShell => {
+                    let Some(cloud_shell) = &scene.cloud_shell else {
+                        break;
+                    };
+                    let sc_type = ScatteringType::Cloud {
+                        asymmetry: cloud_shell.asymmetry,
+                    };
+                    // on the side of the incidence, the light leaving to the other side
+                    // passes through the shell again
+                    let point = self.record.hitpoint - self.now_ray.dir * 0.001;
+                    self.scatter(scene, &point, &sc_type, cloud_shell.single_albedo, rand);
+                }
                 ObjectType::Atmosphere => {
                     in_atmosphere = !in_atmosphere;
                     self.now_ray.org = self.record.hitpoint - self.obj_normal * 0.01;
 
-                    let h = (self.now_ray.org - scene.earth.shape.center).length() - EARTH_RAD;
-
-                    if in_atmosphere && h > KARMAN_LINE {
-                        println!("{}, {}", h, time);
-                        break;
-                    } else if !in_atmosphere && h < KARMAN_LINE {
-                        println!("{}, {}", h, time);
+                    if in_atmosphere != scene.atmosphere.contains(&self.now_ray.org) {
+                        self.stats.lost += 1;
                         break;
                     }
                 }
             }
         }
 
-        self.value
+        // the intensity couples with the polarization
+        self.polarization
+            .as_ref()
+            .map_or(self.value, |p| p.stokes[0])
     }
 }
@@ -0,0 +1,161 @@
+use std::ops::{Add, Mul};
+
+use crate::{
+    constant::{EPS, PN},
+    math::{Vec3, cross, dot},
+};
+
+/*
+(I, Q, U, V) relative to a reference direction x perpendicular to the propagation d,
+Q = I_x - I_y and the angles measured from x toward y = d x x
+*/
+pub type Stokes = [f64; 4];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Mueller(pub [[f64; 4]; 4]);
+
+impl Add for Mueller {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        let mut m = self.0;
+        for (row, r) in m.iter_mut().zip(rhs.0) {
+            for (e, x) in row.iter_mut().zip(r) {
+                *e += x;
+            }
+        }
+        Mueller(m)
+    }
+}
+
+impl Mul for Mueller {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self::Output {
+        let mut m = [[0.; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, e) in row.iter_mut().enumerate() {
+                *e = (0..4).map(|k| self.0[i][k] * rhs.0[k][j]).sum();
+            }
+        }
+        Mueller(m)
+    }
+}
+
+impl Mul<f64> for Mueller {
+    type Output = Self;
+    fn mul(self, rhs: f64) -> Self::Output {
+        Mueller(self.0.map(|row| row.map(|e| e * rhs)))
+    }
+}
+
+impl Mul<Stokes> for Mueller {
+    type Output = Stokes;
+    fn mul(self, rhs: Stokes) -> Self::Output {
+        self.0.map(|row| (0..4).map(|k| row[k] * rhs[k]).sum())
+    }
+}
+
+impl Mueller {
+    pub fn identity() -> Self {
+        let mut m = [[0.; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            row[i] = 1.;
+        }
+        Mueller(m)
+    }
+
+    // unpolarized light whatever the polarization of the incident light
+    pub fn depolarizer() -> Self {
+        let mut m = [[0.; 4]; 4];
+        m[0][0] = 1.;
+        Mueller(m)
+    }
+
+    /*
+    relative to the phase function, x perpendicular to the scattering plane,
+    partly depolarized by the anisotropy of the molecules
+
+    J. E. Hansen and L. D. Travis. Light scattering in planetary atmospheres.
+    In: Space Science Reviews 16 (1974), pp. 527–610.
+    */
+    pub fn rayleigh(cos_theta: f64) -> Self {
+        let delta = (1. - PN) / (1. + PN / 2.);
+        let delta_v = (1. - 2. * PN) / (1. - PN);
+        let cos2 = cos_theta * cos_theta;
+        let p11 = delta * 0.75 * (1. + cos2) + 1. - delta;
+        let p = delta * 0.75 * (1. - cos2) / p11;
+        let p22 = delta * 0.75 * (1. + cos2) / p11;
+        let c = delta * 1.5 * cos_theta / p11;
+        Mueller([
+            [1., p, 0., 0.],
+            [p, p22, 0., 0.],
+            [0., 0., c, 0.],
+            [0., 0., 0., delta_v * c],
+        ])
+    }
+
+    // reflection relative to the reflectance, rs, rp: real amplitude coefficients,
+    // x perpendicular to the plane of incidence
+    pub fn fresnel(rs: f64, rp: f64) -> Self {
+        let r = 0.5 * (rs * rs + rp * rp);
+        if r <= 0. {
+            return Mueller::depolarizer();
+        }
+        let d = 0.5 * (rs * rs - rp * rp) / r;
+        let c = rs * rp / r;
+        Mueller([
+            [1., d, 0., 0.],
+            [d, 1., 0., 0.],
+            [0., 0., c, 0.],
+            [0., 0., 0., c],
+        ])
+    }
+
+    // from the Stokes vectors relative to `from` to those relative to `to`, dir: propagation
+    pub fn rotation(from: &Vec3, to: &Vec3, dir: &Vec3) -> Self {
+        let phi = dot(cross(*from, *to), *dir).atan2(dot(*from, *to));
+        let (sin, cos) = (2. * phi).sin_cos();
+        Mueller([
+            [1., 0., 0., 0.],
+            [0., cos, sin, 0.],
+            [0., -sin, cos, 0.],
+            [0., 0., 0., 1.],
+        ])
+    }
+
+    pub fn column(&self, j: usize) -> Stokes {
+        self.0.map(|row| row[j])
+    }
+}
+
+/*
+reference perpendicular to the plane of the light leaving along wo and arriving from wi,
+both pointing away from the point, the fallback if they are parallel
+*/
+pub fn scattering_frame(wo: &Vec3, wi: &Vec3, fallback: &Vec3) -> Vec3 {
+    let x = cross(*wo, *wi);
+    if x.length() < EPS {
+        *fallback
+    } else {
+        x.normalize()
+    }
+}
+
+// reference perpendicular to dir, as close to x as possible
+pub fn orthogonalize(x: &Vec3, dir: &Vec3) -> Vec3 {
+    let x = *x - *dir * dot(*x, *dir);
+    if x.length() < EPS {
+        scattering_frame(dir, &Vec3(0., 0., 1.), &Vec3(1., 0., 0.))
+    } else {
+        x.normalize()
+    }
+}
+
+// degree and angle [rad] of the linear polarization
+pub fn linear(i: f64, q: f64, u: f64) -> (f64, f64) {
+    let dolp = if i > 0. {
+        ((q * q + u * u).sqrt() / i).min(1.)
+    } else {
+        0.
+    };
+    (dolp, 0.5 * u.atan2(q))
+}
@@ -0,0 +1,194 @@
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    ops::AddAssign,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Statistics {
+    pub paths: u64,
+    pub depth: u64, // sum of the number of bounces of all paths
+    pub null_collisions: u64,
+    pub nee_tries: u64,
+    pub nee_hits: u64, // the sun was not occluded
+    pub invalid: u64,  // NaN, infinite or negative samples discarded
+    pub lost: u64,     // paths stopped at an inconsistent atmosphere boundary
+}
+
+impl Statistics {
+    pub fn mean_depth(&self) -> f64 {
+        self.depth as f64 / self.paths.max(1) as f64
+    }
+
+    pub fn nee_hit_ratio(&self) -> f64 {
+        self.nee_hits as f64 / self.nee_tries.max(1) as f64
+    }
+
+    pub fn to_array(self) -> [u64; 7] {
+        [
+            self.paths,
+            self.depth,
+            self.null_collisions,
+            self.nee_tries,
+            self.nee_hits,
+            self.invalid,
+            self.lost,
+        ]
+    }
+
+    pub fn from_array(a: [u64; 7]) -> Self {
+        Statistics {
+            paths: a[0],
+            depth: a[1],
+            null_collisions: a[2],
+            nee_tries: a[3],
+            nee_hits: a[4],
+            invalid: a[5],
+            lost: a[6],
+        }
+    }
+}
+
+impl AddAssign for Statistics {
+    fn add_assign(&mut self, rhs: Self) {
+        self.paths += rhs.paths;
+        self.depth += rhs.depth;
+        self.null_collisions += rhs.null_collisions;
+        self.nee_tries += rhs.nee_tries;
+        self.nee_hits += rhs.nee_hits;
+        self.invalid += rhs.invalid;
+        self.lost += rhs.lost;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub pass: u32,
+    pub done: u64,  // paths traced
+    pub total: u64, // paths planned, grows when adaptive sampling needs more
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    pub fn ratio(&self) -> f64 {
+        if self.total == 0 {
+            1.
+        } else {
+            self.done as f64 / self.total as f64
+        }
+    }
+
+    pub fn samples_per_sec(&self) -> f64 {
+        self.done as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+
+    pub fn eta(&self) -> Duration {
+        let rate = self.samples_per_sec();
+        if rate == 0. {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(self.total.saturating_sub(self.done) as f64 / rate)
+    }
+}
+
+// workers of a distributed render, as seen by the coordinator
+#[derive(Debug, Clone)]
+pub enum WorkerEvent {
+    Waiting { addr: String, workers: usize },
+    Connected { peer: SocketAddr, threads: u32 },
+    Rejected { peer: SocketAddr, reason: String },
+    Failed { id: usize, error: String }, // its tiles are handed out again
+}
+
+// called from the rendering threads
+pub trait ProgressReporter: Sync {
+    fn update(&self, progress: &Progress);
+    fn finish(&self, progress: &Progress, stats: &Statistics);
+    fn resumed(&self, _path: &str) {}
+    fn worker_event(&self, _event: &WorkerEvent) {}
+    fn warning(&self, _message: &str) {}
+}
+
+pub struct ConsoleReporter {
+    last: Mutex<Instant>,
+}
+
+impl ConsoleReporter {
+    pub fn new() -> Self {
+        ConsoleReporter {
+            last: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Default for ConsoleReporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressReporter for ConsoleReporter {
+    fn update(&self, progress: &Progress) {
+        let mut last = self.last.lock().unwrap();
+        if last.elapsed() < Duration::from_millis(500) {
+            return;
+        }
+        *last = Instant::now();
+
+        let eta = progress.eta().as_secs();
+        print!(
+            "\rpass {:3} {:6.2}%  ETA {:02}:{:02}:{:02}  {:.3e} samples/s   ",
+            progress.pass,
+            progress.ratio() * 100.,
+            eta / 3600,
+            eta / 60 % 60,
+            eta % 60,
+            progress.samples_per_sec()
+        );
+        let _ = io::stdout().flush();
+    }
+
+    fn finish(&self, progress: &Progress, stats: &Statistics) {
+        println!(
+            "\r{} samples in {:.3}sec ({:.3e} samples/s)",
+            progress.done,
+            progress.elapsed.as_secs_f64(),
+            progress.samples_per_sec()
+        );
+        println!("mean path depth:  {:.3}", stats.mean_depth());
+        println!("null collisions:  {}", stats.null_collisions);
+        println!(
+            "NEE hit ratio:    {:.3} ({} / {})",
+            stats.nee_hit_ratio(),
+            stats.nee_hits,
+            stats.nee_tries
+        );
+        println!("invalid samples:  {}", stats.invalid);
+        if stats.lost > 0 {
+            println!("lost paths:       {}", stats.lost);
+        }
+    }
+
+    fn resumed(&self, path: &str) {
+        println!("resumed from {path}");
+    }
+
+    fn worker_event(&self, event: &WorkerEvent) {
+        match event {
+            WorkerEvent::Waiting { addr, workers } => {
+                println!("waiting for {workers} workers on {addr}")
+            }
+            WorkerEvent::Connected { peer, threads } => {
+                println!("worker {peer} connected ({threads} threads)")
+            }
+            WorkerEvent::Rejected { peer, reason } => println!("worker {peer} rejected: {reason}"),
+            WorkerEvent::Failed { id, error } => println!("worker {id} failed: {error}"),
+        }
+    }
+
+    fn warning(&self, message: &str) {
+        println!("warning: {message}");
+    }
+}
@@ -1,3 +1,4 @@
+#[derive(Clone)]
 pub struct XorRand {
     x: u32,
 }
@@ -9,12 +10,20 @@ impl XorRand {
         }
     }
 
+    pub fn from_state(x: u32) -> XorRand {
+        XorRand { x }
+    }
+
+    pub fn state(&self) -> u32 {
+        self.x
+    }
+
     pub fn next01(&mut self) -> f64 {
         let mut x = self.x;
         x = x ^ (x << 13);
         x = x ^ (x >> 7);
         x = x ^ (x << 17);
         self.x = x;
-        (x as f64) / (std::u32::MAX as f64)
+        (x as f64) / (u32::MAX as f64)
     }
 }
@@ -32,3 +32,9 @@ impl HitRecord {
         }
     }
 }
+
+impl Default for HitRecord {
+    fn default() -> Self {
+        Self::new()
+    }
+}
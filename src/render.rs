@@ -1,65 +1,494 @@
-use bmp::{Image, Pixel, px};
-use rayon::{
-    iter::{IndexedParallelIterator, ParallelIterator},
-    slice::ParallelSliceMut,
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
 };
 
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
 use crate::{
     camera::Camera,
+    checkpoint::{self, Checkpoint},
+    constant::INT_Y,
+    distributed::{Distributed, WorkerPool},
+    error::{Error, Result},
+    image::{RenderedImage, SampleStats, Spectral},
     math::{Vec3, is_valid},
     pathtracing::Pathtracing,
+    progress::{ConsoleReporter, Progress, ProgressReporter, Statistics},
     random::XorRand,
     ray::Ray,
-    sampling::{pdf_sample_wavelength, sample_wavelength},
     scene::Scene,
-    spectrum::{color_matching, convert_to_srgb, gamma},
+    spectrum::{XYZ, color_matching},
 };
 
-pub fn render(scene: &Scene, camera: &Camera) {
-    let total_px = camera.pixel_num_w * camera.pixel_num_h;
-    let mut buffer = vec![Vec3::zero(); total_px as usize];
-    let mut img = Image::new(camera.pixel_num_w, camera.pixel_num_h);
-    let coeff_inv =
-        1. / ((camera.spp * camera.sspp.pow(2)) as f64 * 106.91973463815504343620398716858);
-
-    buffer
-        .par_chunks_mut(camera.pixel_num_w as usize)
-        .enumerate()
-        .for_each(|(v, row)| {
-            for u in 0..camera.pixel_num_w {
-                let mut rand = XorRand::new(u * v as u32);
-                let mut accumlated_value = Vec3::zero();
-                for sv in 0..camera.sspp {
-                    for su in 0..camera.sspp {
-                        let pos_on_sensor =
-                            camera.get_pixel_center(u as f64, v as f64, su as f64, sv as f64);
-                        let dir = (pos_on_sensor - camera.eye_pos).normalize();
-                        for _ in 0..camera.spp {
-                            let wavelength = sample_wavelength(&mut rand);
-                            let col_matching = color_matching(wavelength);
-                            let mut tracer =
-                                Pathtracing::new(Ray::new(pos_on_sensor, dir), wavelength);
-
-                            let value = tracer.integrate(scene, &mut rand);
-                            if is_valid(value) {
-                                accumlated_value = accumlated_value
-                                    + value * col_matching / pdf_sample_wavelength(wavelength);
-                            }
-                        }
+pub struct Adaptive {
+    pub max_spp: u32,   // upper limit of spp per pixel
+    pub threshold: f64, // relative standard error of luminance to stop sampling
+    pub batch: u32,     // spp added to an unconverged pixel per pass
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, w: u32, h: u32) -> Self {
+        Rect { x, y, w, h }
+    }
+
+    // (u, v, index in the frame buffer) in row-major order
+    pub fn pixels(&self, frame_w: u32) -> impl Iterator<Item = (u32, u32, usize)> + use<> {
+        let Rect { x, y, w, h } = *self;
+        (y..y + h).flat_map(move |v| (x..x + w).map(move |u| (u, v, (v * frame_w + u) as usize)))
+    }
+
+    fn clamp(&self, frame_w: u32, frame_h: u32) -> Rect {
+        let x = self.x.min(frame_w);
+        let y = self.y.min(frame_h);
+        Rect::new(x, y, self.w.min(frame_w - x), self.h.min(frame_h - y))
+    }
+
+    fn split(&self, size: u32) -> Vec<Rect> {
+        let mut tiles = vec![];
+        for y in (self.y..self.y + self.h).step_by(size as usize) {
+            for x in (self.x..self.x + self.w).step_by(size as usize) {
+                let w = size.min(self.x + self.w - x);
+                let h = size.min(self.y + self.h - y);
+                tiles.push(Rect::new(x, y, w, h));
+            }
+        }
+        tiles
+    }
+}
+
+pub struct RenderConfig {
+    pub adaptive: Option<Adaptive>,
+    pub checkpoint: Option<Checkpoint>,
+    pub tile_size: u32,
+    pub region: Option<Rect>, // render only this rectangle, the rest from a resumed checkpoint
+    pub distributed: Option<Distributed>,
+    pub progress: Option<Box<dyn ProgressReporter>>,
+    pub spectral_bins: usize, // spectral radiance over the camera's wavelengths, 0 to disable
+    pub polarized: bool,      // Stokes vectors for the linear polarization of the luminance
+}
+
+impl RenderConfig {
+    pub fn new() -> Self {
+        RenderConfig {
+            adaptive: None,
+            checkpoint: None,
+            tile_size: 32,
+            region: None,
+            distributed: None,
+            progress: Some(Box::new(ConsoleReporter::new())),
+            spectral_bins: 0,
+            polarized: false,
+        }
+    }
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone)]
+pub struct PixelState {
+    pub xyz: XYZ,    // sum of the mean XYZ of each spp
+    pub lum: f64,    // sum of the mean luminance of each spp
+    pub lum_sq: f64, // sum of the squared mean luminance of each spp
+    pub spp: u32,
+    pub rand: XorRand,
+    pub spectrum: Vec<f64>, // sum of the mean spectral radiance of each spp
+    pub bands: Vec<f64>,    // sum of the mean band-integrated radiance of each spp
+    pub stokes: Vec<f64>,   // sum of the mean Q and U of the luminance, empty if not polarized
+}
+
+impl PixelState {
+    fn new(seed: u32, spectral_bins: usize, bands: usize, polarized: bool) -> Self {
+        PixelState {
+            xyz: Vec3::zero(),
+            lum: 0.,
+            lum_sq: 0.,
+            spp: 0,
+            rand: XorRand::new(seed),
+            spectrum: vec![0.; spectral_bins],
+            bands: vec![0.; bands],
+            stokes: if polarized { vec![0.; 2] } else { Vec::new() },
+        }
+    }
+
+    fn sample_stats(&self) -> SampleStats {
+        SampleStats {
+            spp: self.spp,
+            mean_lum: self.lum / self.spp.max(1) as f64,
+            relative_error: self.relative_error(),
+        }
+    }
+
+    fn value(&self) -> XYZ {
+        if self.spp == 0 {
+            Vec3::zero()
+        } else {
+            self.xyz / self.spp as f64
+        }
+    }
+
+    fn linear_polarization(&self) -> (f64, f64) {
+        let n = self.spp.max(1) as f64;
+        (self.stokes[0] / n, self.stokes[1] / n)
+    }
+
+    // standard error of the mean luminance relative to the mean
+    fn relative_error(&self) -> f64 {
+        if self.spp < 2 {
+            return f64::INFINITY;
+        }
+
+        let n = self.spp as f64;
+        let mean = self.lum / n;
+        let var = ((self.lum_sq - self.lum * mean) / (n - 1.)).max(0.);
+        if var == 0. {
+            0.
+        } else if mean <= 0. {
+            f64::INFINITY
+        } else {
+            (var / n).sqrt() / mean
+        }
+    }
+
+    fn spp_to_add(&self, camera: &Camera, config: &RenderConfig) -> u32 {
+        if self.spp < camera.spp {
+            let batch = config.checkpoint.as_ref().map_or(u32::MAX, |c| c.batch);
+            return (camera.spp - self.spp).min(batch);
+        }
+
+        match &config.adaptive {
+            Some(ad) if self.spp < ad.max_spp && self.relative_error() > ad.threshold => {
+                ad.batch.min(ad.max_spp - self.spp)
+            }
+            _ => 0,
+        }
+    }
+}
+
+// one spp = one sample with a sampled wavelength for each of sspp*sspp sub-pixels
+fn sample_pixel(
+    scene: &Scene,
+    camera: &Camera,
+    u: u32,
+    v: u32,
+    pixel: &mut PixelState,
+    spp: u32,
+    stats: &mut Statistics,
+) {
+    let coeff_inv = 1. / (camera.sspp.pow(2) as f64 * INT_Y);
+    let bins = pixel.spectrum.len();
+    let (min, max) = camera.wavelengths.range();
+    let bin_coeff = bins as f64 / ((max - min) * camera.sspp.pow(2) as f64);
+    let band_coeff = 1. / camera.sspp.pow(2) as f64;
+    let polarized = !pixel.stokes.is_empty();
+
+    for _ in 0..spp {
+        let mut accumlated_value = Vec3::zero();
+        let mut accumlated_stokes = (0., 0.);
+        for sv in 0..camera.sspp {
+            for su in 0..camera.sspp {
+                let pos_on_sensor =
+                    camera.get_pixel_center(u as f64, v as f64, su as f64, sv as f64);
+                let dir = (pos_on_sensor - camera.eye_pos).normalize();
+
+                let wavelength = camera.wavelengths.sample(&mut pixel.rand);
+                let pdf = camera.wavelengths.pdf(wavelength);
+                let col_matching = color_matching(wavelength);
+                let mut tracer = Pathtracing::new(
+                    Ray::new(pos_on_sensor, dir),
+                    wavelength,
+                    camera.pixel_spread,
+                );
+                if polarized {
+                    tracer.set_polarization_frame(camera.polarization_frame(&dir));
+                }
+
+                let value = tracer.integrate(scene, &mut pixel.rand);
+                if is_valid(value) {
+                    let value = value / pdf;
+                    accumlated_value = accumlated_value + value * col_matching;
+                    if bins > 0 {
+                        let bin = (((wavelength - min) / (max - min) * bins as f64) as usize)
+                            .min(bins - 1);
+                        pixel.spectrum[bin] += value * bin_coeff;
+                    }
+                    for (sum, response) in pixel.bands.iter_mut().zip(&camera.bands) {
+                        *sum += value * response.eval(wavelength) * band_coeff;
                     }
+                    if let Some(stokes) = tracer.stokes() {
+                        let y = col_matching.1 / pdf;
+                        accumlated_stokes.0 += stokes[1] * y;
+                        accumlated_stokes.1 += stokes[2] * y;
+                    }
+                } else {
+                    tracer.stats.invalid += 1;
                 }
-                row[u as usize] = accumlated_value * coeff_inv;
+                *stats += tracer.stats;
+            }
+        }
+
+        let xyz = accumlated_value * coeff_inv;
+        pixel.xyz = pixel.xyz + xyz;
+        pixel.lum += xyz.1;
+        pixel.lum_sq += xyz.1 * xyz.1;
+        pixel.spp += 1;
+        if polarized {
+            pixel.stokes[0] += accumlated_stokes.0 * coeff_inv;
+            pixel.stokes[1] += accumlated_stokes.1 * coeff_inv;
+        }
+    }
+}
+
+// pixels of a tile and the spp to add to each of them
+pub struct Job {
+    pub tile: Rect,
+    pub pixels: Vec<PixelState>,
+    pub spp: Vec<u32>,
+}
+
+impl Job {
+    pub fn run(&self, scene: &Scene, camera: &Camera) -> (Vec<PixelState>, Statistics) {
+        let mut pixels = self.pixels.clone();
+        let mut stats = Statistics::default();
+        let coords = self.tile.pixels(camera.pixel_num_w);
+        for (((u, v, _), pixel), spp) in coords.zip(&mut pixels).zip(&self.spp) {
+            if *spp > 0 {
+                sample_pixel(scene, camera, u, v, pixel, *spp, &mut stats);
             }
-            println!("{v}");
-        });
+        }
+        (pixels, stats)
+    }
+
+    // number of paths to trace
+    pub fn paths(&self, camera: &Camera) -> u64 {
+        self.spp.iter().map(|s| *s as u64).sum::<u64>() * camera.sspp.pow(2) as u64
+    }
+}
 
-    for i in 0..total_px {
-        let v = i / camera.pixel_num_w;
-        let u = i - camera.pixel_num_w * v;
-        let xyz = buffer[i as usize];
+pub fn render(scene: &Scene, camera: &Camera, config: &RenderConfig) -> Result<RenderedImage> {
+    let (min, max) = camera.wavelengths.range();
+    if !(min > 0. && min < max) {
+        return Err(Error::InvalidArgument(format!(
+            "invalid wavelength range {min} - {max} nm"
+        )));
+    }
 
-        let rgb = gamma(convert_to_srgb(&xyz));
-        img.set_pixel(u, v as u32, px!(rgb.0, rgb.1, rgb.2));
+    let total_px = camera.pixel_num_w * camera.pixel_num_h;
+    let hash = checkpoint::config_hash(scene, camera);
+
+    let resumed = match &config.checkpoint {
+        Some(ckpt) if ckpt.resume && std::path::Path::new(&ckpt.path).exists() => {
+            let buffer = checkpoint::load(&ckpt.path, hash, total_px as usize).map_err(|e| {
+                Error::Checkpoint {
+                    path: ckpt.path.clone(),
+                    message: e.to_string(),
+                }
+            })?;
+            if buffer
+                .iter()
+                .any(|p| p.spectrum.len() != config.spectral_bins)
+            {
+                return Err(Error::Checkpoint {
+                    path: ckpt.path.clone(),
+                    message: "different number of spectral bins".to_string(),
+                });
+            }
+            if buffer
+                .iter()
+                .any(|p| p.stokes.is_empty() == config.polarized)
+            {
+                return Err(Error::Checkpoint {
+                    path: ckpt.path.clone(),
+                    message: "different polarization mode".to_string(),
+                });
+            }
+            if let Some(reporter) = &config.progress {
+                reporter.resumed(&ckpt.path);
+            }
+            Some(buffer)
+        }
+        _ => None,
+    };
+    // the rest of the image would be left black, and saved so
+    if config.region.is_some() && resumed.is_none() {
+        return Err(Error::InvalidArgument(
+            "a region needs an existing checkpoint to resume the rest of the image from"
+                .to_string(),
+        ));
     }
-    let _ = img.save("render.bmp");
+    let mut buffer: Vec<PixelState> = resumed.unwrap_or_else(|| {
+        (0..total_px)
+            .map(|i| {
+                // distinct for every pixel, spread so that neighbours start far apart
+                PixelState::new(
+                    i.wrapping_mul(0x9e37_79b9),
+                    config.spectral_bins,
+                    camera.bands.len(),
+                    config.polarized,
+                )
+            })
+            .collect()
+    });
+
+    let tiles = config
+        .region
+        .unwrap_or(Rect::new(0, 0, camera.pixel_num_w, camera.pixel_num_h))
+        .clamp(camera.pixel_num_w, camera.pixel_num_h)
+        .split(config.tile_size.max(1));
+
+    let mut pool = match &config.distributed {
+        Some(dist) => Some(
+            WorkerPool::listen(dist, hash, config.progress.as_deref()).map_err(Error::Network)?,
+        ),
+        None => None,
+    };
+
+    let paths_per_spp = camera.sspp.pow(2) as u64;
+    let mut total = tiles
+        .iter()
+        .flat_map(|tile| tile.pixels(camera.pixel_num_w))
+        .map(|(_, _, i)| camera.spp.saturating_sub(buffer[i].spp) as u64 * paths_per_spp)
+        .sum();
+    let done = AtomicU64::new(0);
+    let mut stats = Statistics::default();
+    let start = Instant::now();
+
+    let mut last_save = Instant::now();
+    for pass in 0.. {
+        let jobs: Vec<Job> = tiles
+            .iter()
+            .filter_map(|tile| {
+                let spp: Vec<u32> = tile
+                    .pixels(camera.pixel_num_w)
+                    .map(|(_, _, i)| buffer[i].spp_to_add(camera, config))
+                    .collect();
+                if spp.iter().all(|s| *s == 0) {
+                    return None;
+                }
+                let pixels = tile
+                    .pixels(camera.pixel_num_w)
+                    .map(|(_, _, i)| buffer[i].clone())
+                    .collect();
+                Some(Job {
+                    tile: *tile,
+                    pixels,
+                    spp,
+                })
+            })
+            .collect();
+        if jobs.is_empty() {
+            break;
+        }
+
+        // samples beyond camera.spp are not known until adaptive sampling asks for them
+        total += jobs
+            .iter()
+            .flat_map(|job| job.pixels.iter().zip(&job.spp))
+            .map(|(p, s)| (p.spp + s).saturating_sub(p.spp.max(camera.spp)) as u64)
+            .sum::<u64>()
+            * paths_per_spp;
+
+        let on_done = |job: &Job| {
+            let done = done.fetch_add(job.paths(camera), Ordering::Relaxed) + job.paths(camera);
+            if let Some(reporter) = &config.progress {
+                reporter.update(&Progress {
+                    pass,
+                    done,
+                    total,
+                    elapsed: start.elapsed(),
+                });
+            }
+        };
+
+        let results: Vec<(Vec<PixelState>, Statistics)> = match &mut pool {
+            Some(pool) => pool.run(scene, camera, &jobs, &on_done, config.progress.as_deref()),
+            None => (&jobs)
+                .into_par_iter()
+                .map(|job| {
+                    let result = job.run(scene, camera);
+                    on_done(job);
+                    result
+                })
+                .collect(),
+        };
+
+        for (job, (pixels, job_stats)) in jobs.iter().zip(results) {
+            let tile = job.tile;
+            for ((_, _, i), pixel) in tile.pixels(camera.pixel_num_w).zip(pixels) {
+                buffer[i] = pixel;
+            }
+            stats += job_stats;
+        }
+
+        if let Some(ckpt) = &config.checkpoint
+            && last_save.elapsed() >= ckpt.interval
+        {
+            save_checkpoint(ckpt, hash, &buffer)?;
+            last_save = Instant::now();
+        }
+    }
+
+    if let Some(ckpt) = &config.checkpoint {
+        save_checkpoint(ckpt, hash, &buffer)?;
+    }
+
+    if let Some(reporter) = &config.progress {
+        let done = done.load(Ordering::Relaxed);
+        let progress = Progress {
+            pass: 0,
+            done,
+            total: done,
+            elapsed: start.elapsed(),
+        };
+        reporter.finish(&progress, &stats);
+    }
+
+    let spectral = (config.spectral_bins > 0).then(|| Spectral {
+        bins: config.spectral_bins,
+        min,
+        max,
+        data: buffer
+            .iter()
+            .flat_map(|p| p.spectrum.iter().map(|s| s / p.spp.max(1) as f64))
+            .collect(),
+    });
+
+    Ok(RenderedImage {
+        width: camera.pixel_num_w,
+        height: camera.pixel_num_h,
+        xyz: buffer.iter().map(|p| p.value()).collect(),
+        spectral,
+        bands: (0..camera.bands.len())
+            .map(|k| {
+                buffer
+                    .iter()
+                    .map(|p| p.bands[k] / p.spp.max(1) as f64)
+                    .collect()
+            })
+            .collect(),
+        linear_polarization: config
+            .polarized
+            .then(|| buffer.iter().map(|p| p.linear_polarization()).collect()),
+        samples: buffer.iter().map(|p| p.sample_stats()).collect(),
+        stats,
+    })
+}
+
+fn save_checkpoint(ckpt: &Checkpoint, hash: u64, buffer: &[PixelState]) -> Result<()> {
+    checkpoint::save(&ckpt.path, hash, buffer).map_err(|e| Error::Checkpoint {
+        path: ckpt.path.clone(),
+        message: format!("failed to save: {e}"),
+    })
 }
@@ -1,14 +1,133 @@
+use std::hash::{Hash, Hasher};
+
 use crate::{
     constant::{EPS, INT_XYZ, PI, PI_INV},
+    error::{Error, Result},
     math::{Vec3, cross, dot, fmax},
+    mie::MieTables,
+    polarization::Mueller,
     random::XorRand,
     spectrum::color_matching,
 };
 
 #[derive(Debug)]
-pub enum ScatteringType {
+pub enum ScatteringType<'a> {
     Rayleigh,
-    Mie,
+    Mie {
+        phase: &'a MiePhase,
+        wavelength: f64,
+    },
+    Cloud {
+        asymmetry: f64,
+    },
+}
+
+// phase function of the aerosols
+#[derive(Debug)]
+pub enum MiePhase {
+    HenyeyGreenstein { g: f64 },
+    // forward lobe g1 with the weight and backward lobe g2 < 0
+    DoubleHenyeyGreenstein { g1: f64, g2: f64, weight: f64 },
+    /*
+    W. M. Cornette and J. G. Shanks. Physically reasonable analytic expression
+    for the single-scattering phase function. In: Applied Optics 31.16 (1992), pp. 3152–3160.
+    */
+    CornetteShanks { g: f64 },
+    LorenzMie(MieTables),
+}
+
+impl MiePhase {
+    pub fn new() -> Self {
+        MiePhase::HenyeyGreenstein { g: MIE_ASYMMETRY }
+    }
+
+    fn sample(&self, prev_dir: &Vec3, wavelength: f64, rand: &mut XorRand) -> (Vec3, f64) {
+        match self {
+            MiePhase::HenyeyGreenstein { g } => sample_phase_hg(prev_dir, *g, rand),
+            MiePhase::DoubleHenyeyGreenstein { g1, g2, weight } => {
+                let g = if rand.next01() < *weight { g1 } else { g2 };
+                let (dir, _) = sample_phase_hg(prev_dir, *g, rand);
+                (dir, self.pdf(&dir, prev_dir, wavelength))
+            }
+            // rejection from Henyey-Greenstein, accepted with (1 + cos^2) / 2
+            MiePhase::CornetteShanks { g } => loop {
+                let (dir, _) = sample_phase_hg(prev_dir, *g, rand);
+                let cos = dot(*prev_dir, dir);
+                if 2. * rand.next01() < 1. + cos * cos {
+                    return (dir, self.pdf(&dir, prev_dir, wavelength));
+                }
+            },
+            MiePhase::LorenzMie(tables) => {
+                let table = tables.get(wavelength);
+                let cos_theta = table.sample(rand.next01(), rand.next01());
+                let dir = around(prev_dir, -cos_theta, rand);
+                (dir, table.eval(cos_theta))
+            }
+        }
+    }
+
+    fn pdf(&self, dir: &Vec3, prev_dir: &Vec3, wavelength: f64) -> f64 {
+        match self {
+            MiePhase::HenyeyGreenstein { g } => pdf_phase_hg(dir, prev_dir, *g),
+            MiePhase::DoubleHenyeyGreenstein { g1, g2, weight } => {
+                weight * pdf_phase_hg(dir, prev_dir, *g1)
+                    + (1. - weight) * pdf_phase_hg(dir, prev_dir, *g2)
+            }
+            MiePhase::CornetteShanks { g } => {
+                let cos = dot(*prev_dir, *dir);
+                pdf_phase_hg(dir, prev_dir, *g) * 1.5 * (1. + cos * cos) / (2. + g * g)
+            }
+            MiePhase::LorenzMie(tables) => tables.get(wavelength).eval(-dot(*prev_dir, *dir)),
+        }
+    }
+
+    // the analytic ones are given the polarization of Rayleigh scattering
+    fn mueller(&self, dir: &Vec3, prev_dir: &Vec3, wavelength: f64) -> Mueller {
+        let cos_theta = -dot(*prev_dir, *dir);
+        match self {
+            MiePhase::LorenzMie(tables) => {
+                let [p, s33, s34] = tables.get(wavelength).polarization(cos_theta);
+                Mueller([
+                    [1., p, 0., 0.],
+                    [p, 1., 0., 0.],
+                    [0., 0., s33, -s34],
+                    [0., 0., s34, s33],
+                ])
+            }
+            _ => Mueller::rayleigh(cos_theta),
+        }
+    }
+}
+
+impl Default for MiePhase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hash for MiePhase {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            MiePhase::HenyeyGreenstein { g } => {
+                0.hash(state);
+                g.to_bits().hash(state);
+            }
+            MiePhase::DoubleHenyeyGreenstein { g1, g2, weight } => {
+                1.hash(state);
+                g1.to_bits().hash(state);
+                g2.to_bits().hash(state);
+                weight.to_bits().hash(state);
+            }
+            MiePhase::CornetteShanks { g } => {
+                2.hash(state);
+                g.to_bits().hash(state);
+            }
+            MiePhase::LorenzMie(tables) => {
+                3.hash(state);
+                tables.hash(state);
+            }
+        }
+    }
 }
 
 pub fn sample_cos_hemisphere(normal: &Vec3, rand: &mut XorRand) -> Vec3 {
@@ -69,6 +188,167 @@ pub fn pdf_sample_wavelength(wavelength: f64) -> f64 {
 
 // ----- //
 
+// distribution of the wavelengths traced for the pixels
+#[derive(Debug, Clone, PartialEq)]
+pub enum WavelengthSampling {
+    ColorMatching,                  // 380nm - 780nm in proportion to x + y + z
+    Uniform { min: f64, max: f64 }, // [nm]
+    Tabulated(TabulatedPdf),
+}
+
+impl WavelengthSampling {
+    pub fn new() -> Self {
+        WavelengthSampling::ColorMatching
+    }
+
+    pub fn sample(&self, rand: &mut XorRand) -> f64 {
+        match self {
+            WavelengthSampling::ColorMatching => sample_wavelength(rand),
+            WavelengthSampling::Uniform { min, max } => min + rand.next01() * (max - min),
+            WavelengthSampling::Tabulated(pdf) => pdf.sample(rand),
+        }
+    }
+
+    // [1/nm]
+    pub fn pdf(&self, wavelength: f64) -> f64 {
+        match self {
+            WavelengthSampling::ColorMatching => pdf_sample_wavelength(wavelength),
+            WavelengthSampling::Uniform { min, max } => {
+                if (*min..=*max).contains(&wavelength) {
+                    1. / (max - min)
+                } else {
+                    0.
+                }
+            }
+            WavelengthSampling::Tabulated(pdf) => pdf.pdf(wavelength),
+        }
+    }
+
+    // (min, max) [nm]
+    pub fn range(&self) -> (f64, f64) {
+        match self {
+            WavelengthSampling::ColorMatching => (380., 780.),
+            WavelengthSampling::Uniform { min, max } => (*min, *max),
+            WavelengthSampling::Tabulated(pdf) => {
+                (pdf.wavelengths[0], *pdf.wavelengths.last().unwrap())
+            }
+        }
+    }
+}
+
+impl Default for WavelengthSampling {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hash for WavelengthSampling {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            WavelengthSampling::ColorMatching => 0.hash(state),
+            WavelengthSampling::Uniform { min, max } => {
+                1.hash(state);
+                min.to_bits().hash(state);
+                max.to_bits().hash(state);
+            }
+            WavelengthSampling::Tabulated(pdf) => {
+                2.hash(state);
+                pdf.hash(state);
+            }
+        }
+    }
+}
+
+// constant between the wavelengths at the mean of their weights, sampled exactly
+#[derive(Debug, Clone, PartialEq)]
+pub struct TabulatedPdf {
+    wavelengths: Vec<f64>, // ascending
+    cdf: Vec<f64>,         // up to the end of each interval
+}
+
+impl TabulatedPdf {
+    // weights: (wavelength [nm] ascending, relative density)
+    pub fn new(weights: &[(f64, f64)]) -> Result<Self> {
+        if weights.len() < 2
+            || weights.windows(2).any(|w| w[0].0 >= w[1].0)
+            || weights.iter().any(|(_, d)| d.is_nan() || *d < 0.)
+        {
+            return Err(Error::InvalidArgument(
+                "the wavelengths must ascend and the weights must not be negative".to_string(),
+            ));
+        }
+
+        let masses: Vec<f64> = weights
+            .windows(2)
+            .map(|w| 0.5 * (w[0].1 + w[1].1) * (w[1].0 - w[0].0))
+            .collect();
+        let total: f64 = masses.iter().sum();
+        if total <= 0. {
+            return Err(Error::InvalidArgument(
+                "the weights must not be all zero".to_string(),
+            ));
+        }
+
+        let mut acc = 0.;
+        let cdf = masses
+            .iter()
+            .map(|m| {
+                acc += m / total;
+                acc
+            })
+            .collect();
+        Ok(TabulatedPdf {
+            wavelengths: weights.iter().map(|(w, _)| *w).collect(),
+            cdf,
+        })
+    }
+
+    fn mass(&self, i: usize) -> f64 {
+        self.cdf[i] - if i == 0 { 0. } else { self.cdf[i - 1] }
+    }
+
+    // in [w0, w1) of an interval with some mass, as pdf() looks it up
+    fn sample(&self, rand: &mut XorRand) -> f64 {
+        let u = rand.next01();
+        let mut i = self
+            .cdf
+            .partition_point(|c| *c <= u)
+            .min(self.cdf.len() - 1);
+        while self.mass(i) <= 0. {
+            i -= 1;
+        }
+        let (w0, w1) = (self.wavelengths[i], self.wavelengths[i + 1]);
+        (w0 + (w1 - w0) * rand.next01()).min(w1.next_down())
+    }
+
+    // the intervals are half-open, the last one also holds its end
+    fn pdf(&self, wavelength: f64) -> f64 {
+        let last = self.wavelengths.len() - 1;
+        if !(self.wavelengths[0]..=self.wavelengths[last]).contains(&wavelength) {
+            return 0.;
+        }
+        let i = self
+            .wavelengths
+            .partition_point(|w| *w <= wavelength)
+            .min(last);
+        self.mass(i - 1) / (self.wavelengths[i] - self.wavelengths[i - 1])
+    }
+}
+
+// every entry, there is one more wavelength than the cdf
+impl Hash for TabulatedPdf {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.wavelengths.len().hash(state);
+        for w in &self.wavelengths {
+            w.to_bits().hash(state);
+        }
+        self.cdf.len().hash(state);
+        for c in &self.cdf {
+            c.to_bits().hash(state);
+        }
+    }
+}
+
 fn sample_phase_rayleigh(prev_dir: &Vec3, rand: &mut XorRand) -> (Vec3, f64) {
     let w = *prev_dir;
     let u = if w.0.abs() > EPS {
@@ -98,7 +378,8 @@ fn pdf_phase_rayleigh(dir: &Vec3, prev_dir: &Vec3) -> f64 {
     3. * (1. + dot * dot) / (16. * PI)
 }
 
-fn sample_phase_mie(prev_dir: &Vec3, rand: &mut XorRand) -> (Vec3, f64) {
+// cos_theta: measured from prev_dir
+fn around(prev_dir: &Vec3, cos_theta: f64, rand: &mut XorRand) -> Vec3 {
     let w = *prev_dir;
     let u = if w.0.abs() > EPS {
         cross(Vec3(0., 1., 0.), w).normalize()
@@ -108,9 +389,26 @@ fn sample_phase_mie(prev_dir: &Vec3, rand: &mut XorRand) -> (Vec3, f64) {
     let v = cross(w, u);
 
     let phi = 2. * PI * rand.next01();
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    u * sin_theta * phi.cos() + v * sin_theta * phi.sin() + w * cos_theta
+}
 
-    let g = 0.8;
-    let cos_theta = {
+// Henyey-Greenstein
+fn sample_phase_hg(prev_dir: &Vec3, g: f64, rand: &mut XorRand) -> (Vec3, f64) {
+    let w = *prev_dir;
+    let u = if w.0.abs() > EPS {
+        cross(Vec3(0., 1., 0.), w).normalize()
+    } else {
+        cross(Vec3(1., 0., 0.), w).normalize()
+    };
+    let v = cross(w, u);
+
+    let phi = 2. * PI * rand.next01();
+
+    // measured from prev_dir, which points backward
+    let cos_theta = if g.abs() < 1e-3 {
+        1. - 2. * rand.next01()
+    } else {
         let r = (1. - g * g) / (1. + g - 2. * g * rand.next01());
         -1. / (2. * g) * (1. + g * g - r * r)
     };
@@ -122,24 +420,56 @@ fn sample_phase_mie(prev_dir: &Vec3, rand: &mut XorRand) -> (Vec3, f64) {
     )
 }
 
-fn pdf_phase_mie(dir: &Vec3, prev_dir: &Vec3) -> f64 {
+fn pdf_phase_hg(dir: &Vec3, prev_dir: &Vec3, g: f64) -> f64 {
     let dot = dot(*prev_dir, *dir);
-    let g = 0.8;
     1. / (4. * PI) * (1. - g * g) / (1. + g * g + 2. * g * dot).powf(1.5)
 }
 
+const MIE_ASYMMETRY: f64 = 0.8;
+
 pub fn sample_phase(sc_type: &ScatteringType, prev_dir: &Vec3, rand: &mut XorRand) -> (Vec3, f64) {
-    if let ScatteringType::Rayleigh = sc_type {
-        sample_phase_rayleigh(prev_dir, rand)
-    } else {
-        sample_phase_mie(prev_dir, rand)
+    match sc_type {
+        ScatteringType::Rayleigh => sample_phase_rayleigh(prev_dir, rand),
+        ScatteringType::Mie { phase, wavelength } => phase.sample(prev_dir, *wavelength, rand),
+        ScatteringType::Cloud { asymmetry } => sample_phase_hg(prev_dir, *asymmetry, rand),
     }
 }
 
 pub fn pdf_phase(sc_type: &ScatteringType, dir: &Vec3, prev_dir: &Vec3) -> f64 {
-    if let ScatteringType::Rayleigh = sc_type {
-        pdf_phase_rayleigh(dir, prev_dir)
-    } else {
-        pdf_phase_mie(dir, prev_dir)
+    match sc_type {
+        ScatteringType::Rayleigh => pdf_phase_rayleigh(dir, prev_dir),
+        ScatteringType::Mie { phase, wavelength } => phase.pdf(dir, prev_dir, *wavelength),
+        ScatteringType::Cloud { asymmetry } => pdf_phase_hg(dir, prev_dir, *asymmetry),
+    }
+}
+
+// relative to pdf_phase(), the clouds scatter many times and leave the light unpolarized
+pub fn mueller_phase(sc_type: &ScatteringType, dir: &Vec3, prev_dir: &Vec3) -> Mueller {
+    match sc_type {
+        ScatteringType::Mie { phase, wavelength } => phase.mueller(dir, prev_dir, *wavelength),
+        ScatteringType::Rayleigh => Mueller::rayleigh(-dot(*prev_dir, *dir)),
+        ScatteringType::Cloud { .. } => Mueller::depolarizer(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the ends of the range and the samples are inside an interval with some mass
+    #[test]
+    fn tabulated_pdf_covers_its_samples() {
+        let pdf = TabulatedPdf::new(&[(400., 0.), (500., 0.), (600., 1.), (700., 2.)]).unwrap();
+        assert_eq!(pdf.pdf(400.), 0.);
+        assert!(pdf.pdf(700.) > 0.);
+        assert_eq!(pdf.pdf(700.), pdf.pdf(650.));
+        assert_eq!(pdf.pdf(700.1), 0.);
+
+        let mut rand = XorRand::new(7);
+        for _ in 0..10000 {
+            let w = pdf.sample(&mut rand);
+            assert!((500. ..700.).contains(&w));
+            assert!(pdf.pdf(w) > 0.);
+        }
     }
 }
@@ -1,10 +1,18 @@
+use std::hash::{Hash, Hasher};
+
 use crate::{
-    constant::{E, EARTH_RAD, EARTH_TO_SUN, KARMAN_LINE, NS, PI, PN, SUN_LIGHT, SUN_RAD},
+    aerosol::AerosolLayer,
+    cloud::{CloudLayer, CloudShell},
+    constant::{E, EARTH_RAD, EARTH_TO_SUN, INF, KARMAN_LINE, NS, PI, PN, SUN_LIGHT, SUN_RAD},
+    error::{Error, Result},
+    gas::AbsorbingGas,
     math::{Point3, Vec3, dot, fmax},
+    ocean::Ocean,
+    progress::Statistics,
     random::XorRand,
     ray::{HitRecord, Ray},
     sampling::ScatteringType,
-    sphere::{Earth, ObjectType, Sphere},
+    sphere::{Earth, Figure, ObjectType, Sphere},
     texture::Texture,
 };
 
@@ -20,14 +28,53 @@ impl NeeResult {
     }
 }
 
+// bending of the rays by the refractive index of the air, followed in straight steps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Refraction {
+    pub step: f64, // at the ground [km], longer above as the bending weakens with the density
+}
+
+impl Refraction {
+    pub fn new() -> Self {
+        Refraction { step: 5. }
+    }
+}
+
+impl Default for Refraction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hash for Refraction {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.step.to_bits().hash(state);
+    }
+}
+
+// of the refractivity [km], the fit of the Rayleigh profile is flat at the ground
+const REFRACTIVITY_SCALE_HEIGHT: f64 = 8.;
+
 pub struct Scene<'a> {
     pub sun: Sphere,
     pub earth: Earth<'a>,
     pub atmosphere: Sphere,
+    pub ocean: Ocean, // surface with a specular value >= 120 or of water
+    pub clouds: Vec<CloudLayer>,
+    pub cloud_shell: Option<CloudShell>,
+    pub refraction: Option<Refraction>,
+    pub aerosols: Vec<AerosolLayer>,
+    pub gases: Vec<AbsorbingGas>,
 }
 
 impl<'a> Scene<'a> {
-    pub fn new(month: u32, earth_texture: &'a Texture) -> Self {
+    pub fn new(month: u32, earth_texture: &'a Texture) -> Result<Self> {
+        if !(1..=12).contains(&month) {
+            return Err(Error::InvalidArgument(format!(
+                "month must be 1 - 12, got {month}"
+            )));
+        }
+
         let sun = Sphere::new(Vec3::zero(), SUN_RAD, ObjectType::Sun);
 
         let earth_center = {
@@ -35,155 +82,355 @@ impl<'a> Scene<'a> {
             let r = EARTH_RAD + SUN_RAD + EARTH_TO_SUN;
             Vec3(r * earth_phi.cos(), r * earth_phi.sin(), 0.)
         };
-        let earth = {
-            let shape = Sphere::new(earth_center, EARTH_RAD, ObjectType::Earth);
-            Earth::new(shape, earth_texture)
-        };
-
-        let atmosphere = Sphere::new(
-            earth_center,
-            EARTH_RAD + KARMAN_LINE,
-            ObjectType::Atmosphere,
-        );
+        let earth = Earth::new(earth_center, Figure::Sphere, earth_texture, month);
+        let atmosphere = shell(&earth.shape, KARMAN_LINE, ObjectType::Atmosphere);
 
-        Scene {
+        Ok(Scene {
             sun,
             earth,
             atmosphere,
-        }
+            ocean: Ocean::new(5.),
+            clouds: Vec::new(),
+            cloud_shell: None,
+            refraction: None,
+            aerosols: vec![AerosolLayer::boundary_layer()],
+            gases: Vec::new(),
+        })
+    }
+
+    // shape of the Earth and the atmosphere, to be set before the camera
+    pub fn set_figure(&mut self, figure: Figure) {
+        self.earth.set_figure(figure);
+        self.atmosphere = shell(&self.earth.shape, KARMAN_LINE, ObjectType::Atmosphere);
     }
 
     pub fn hit(&self, ray: &Ray, record: &mut HitRecord) -> bool {
-        let mut is_hit = self.earth.shape.hit(ray, record);
-        is_hit = is_hit | self.sun.hit(ray, record);
+        let mut is_hit = self.earth.hit(ray, record);
+        is_hit |= self.sun.hit(ray, record);
         is_hit | self.atmosphere.hit(ray, record)
     }
 
     pub fn in_atmosphere(&self, point: &Point3) -> bool {
-        let d = (*point - self.earth.shape.center).length() - EARTH_RAD;
-        d > 0. && d < KARMAN_LINE
+        self.atmosphere.contains(point) && !self.earth.shape.contains(point)
+    }
+
+    // above the Earth's sphere or spheroid [km]
+    pub fn altitude(&self, point: &Point3) -> f64 {
+        fmax(self.earth.shape.altitude(point), 0.)
     }
 
     // wavelength: [nm]
     pub fn scattering_coeff_rayleigh(&self, point: &Point3, wavelength: f64) -> f64 {
-        let h = fmax((*point - self.earth.shape.center).length() - EARTH_RAD, 0.);
-        let ior = get_ior(wavelength);
+        rayleigh_at(self.altitude(point), wavelength)
+    }
 
-        let mu0 = {
-            let w_cm = wavelength * 1e-7; // [nm] -> [cm]
-            let l = 24. * PI.powi(3) / (w_cm.powi(4) * NS);
-            let m = ((ior * ior - 1.) / (ior * ior + 2.)).powi(2);
-            let r = (6. + 3. * PN) / (6. - 7. * PN);
+    // (scattering, absorption) of all the aerosol layers [1/km]
+    pub fn coeff_mie(&self, point: &Point3, wavelength: f64) -> (f64, f64) {
+        let h = self.altitude(point);
+        self.aerosols.iter().fold((0., 0.), |acc, a| {
+            let (s, k) = a.coeff(h, wavelength);
+            (acc.0 + s, acc.1 + k)
+        })
+    }
 
-            l * m * r
-        };
+    // of all the media [1/km]
+    pub fn extinction(&self, point: &Point3, wavelength: f64) -> f64 {
+        let h = self.altitude(point);
+        let mie = self.coeff_mie(point, wavelength);
+        let clouds: f64 = self
+            .clouds
+            .iter()
+            .map(|c| c.extinction() * c.density(&self.earth, point, h))
+            .sum();
+        let gases: f64 = self.gases.iter().map(|g| g.coeff(h, wavelength)).sum();
+        rayleigh_at(h, wavelength) + mie.0 + mie.1 + clouds + gases
+    }
 
-        let coeff = {
-            let a = 0.07771971;
-            let b = 1.16364243;
+    // of the air, the refractivity falls off with the density
+    pub fn ior(&self, point: &Point3, wavelength: f64) -> f64 {
+        let h = self.altitude(point);
+        1. + (get_ior(wavelength) - 1.) * (-h / REFRACTIVITY_SCALE_HEIGHT).exp()
+    }
 
-            E.powf(-a * h.powf(b))
-        };
+    // direction after a step along a curved ray, d(n dir)/ds = grad n, point: middle of the step
+    fn bend(&self, point: &Point3, dir: &Vec3, step: f64, wavelength: f64) -> Vec3 {
+        let n = self.ior(point, wavelength);
+        let grad = self.earth.shape.normal(point) * (-(n - 1.) / REFRACTIVITY_SCALE_HEIGHT);
+        (*dir + (grad - *dir * dot(grad, *dir)) * (step / n)).normalize()
+    }
 
-        mu0 * 1e5 * coeff
+    // of a straight step of a curved ray from the point, None if the border is closer
+    fn step(&self, point: &Point3, to_border: f64) -> Option<f64> {
+        let refraction = self.refraction?;
+        let step = refraction.step * (self.altitude(point) / REFRACTIVITY_SCALE_HEIGHT).exp();
+        (step < to_border).then_some(step)
     }
 
-    pub fn coeff_mie(&self, point: &Point3) -> (f64, f64) {
-        // return (scattering, absorption)
-        let h = fmax((*point - self.earth.shape.center).length() - EARTH_RAD, 0.);
-        (4. * 1e-3 * E.powf(-h / 1.2), 4.4 * 1e-3 * E.powf(-h / 1.2))
+    // of the center of the Sun, raised by the refraction
+    fn sun_direction(&self, org: &Point3, wavelength: f64) -> Vec3 {
+        let dir = (self.sun.center - *org).normalize();
+        if self.refraction.is_none() {
+            return dir;
+        }
+
+        // the deviation of the ray leaving in the geometric direction is taken back
+        let mut ray = Ray::new(*org, dir);
+        loop {
+            let mut record = HitRecord::new();
+            let _ =
+                self.earth.shape.hit(&ray, &mut record) | self.atmosphere.hit(&ray, &mut record);
+            let Some(step) = self.step(&ray.org, record.distance) else {
+                break;
+            };
+            let mid = ray.org + ray.dir * (step / 2.);
+            ray = Ray::new(
+                ray.org + ray.dir * step,
+                self.bend(&mid, &ray.dir, step, wavelength),
+            );
+        }
+        (dir * 2. - ray.dir).normalize()
     }
 
-    pub fn get_atmos_coeff(
+    // medium scattering at a collision in proportion to its extinction, and its albedo
+    pub fn sample_medium(
         &self,
         point: &Point3,
         wavelength: f64,
-        sc_type: &ScatteringType,
-    ) -> (f64, f64) {
-        if let ScatteringType::Rayleigh = sc_type {
-            (self.scattering_coeff_rayleigh(point, wavelength), 0.)
-        } else {
-            self.coeff_mie(point)
+        rand: &mut XorRand,
+    ) -> (ScatteringType<'_>, f64) {
+        let h = self.altitude(point);
+        let rayleigh = rayleigh_at(h, wavelength);
+
+        let mut x = rand.next01() * self.extinction(point, wavelength) - rayleigh;
+        if x < 0. {
+            return (ScatteringType::Rayleigh, 1.);
+        }
+        for aerosol in &self.aerosols {
+            let (scattering, absorption) = aerosol.coeff(h, wavelength);
+            x -= scattering + absorption;
+            if x < 0. {
+                let sc_type = ScatteringType::Mie {
+                    phase: &aerosol.phase,
+                    wavelength,
+                };
+                return (sc_type, scattering / (scattering + absorption));
+            }
         }
+        for cloud in &self.clouds {
+            x -= cloud.extinction() * cloud.density(&self.earth, point, h);
+            if x < 0. {
+                let sc_type = ScatteringType::Cloud {
+                    asymmetry: cloud.asymmetry,
+                };
+                return (sc_type, cloud.single_albedo);
+            }
+        }
+        for gas in &self.gases {
+            x -= gas.coeff(h, wavelength);
+            if x < 0. {
+                // absorbed
+                return (ScatteringType::Rayleigh, 0.);
+            }
+        }
+        (ScatteringType::Rayleigh, 1.)
     }
 
-    fn altitude_min_point(&self, ray: &Ray) -> Point3 {
-        let po = ray.org - self.earth.shape.center;
-        let dot = dot(po.normalize(), ray.dir);
+    /*
+    piecewise constant majorant, (end, majorant) of each segment up to to_border
+
+    the air is densest at the lowest point of the ray, an aerosol layer or an absorbing gas
+    at its densest between the lowest and the highest points,
+    the clouds raise the majorant only between their base and top
+    */
+    fn majorants(&self, ray: &Ray, to_border: f64, wavelength: f64) -> Vec<(f64, f64)> {
+        // shells with a margin for the altitudes above a spheroid not being parallel to them
+        let margin = 0.1;
+
+        let low = self.earth.shape.altitude_min(ray);
+        let high = fmax(
+            self.earth.shape.altitude(&ray.org),
+            self.earth.shape.altitude(&(ray.org + ray.dir * to_border)),
+        ) + margin;
+        let aerosols: f64 = self
+            .aerosols
+            .iter()
+            .map(|a| a.max_extinction(low, high, wavelength))
+            .sum();
+        let gases: f64 = self
+            .gases
+            .iter()
+            .map(|g| g.max_coeff(low, high, wavelength))
+            .sum();
+        let majorant = rayleigh_at(low, wavelength) + aerosols + gases;
+        if self.clouds.is_empty() {
+            return vec![(to_border, majorant)];
+        }
 
-        if dot > 0. {
-            ray.org
-        } else {
-            let mut record = HitRecord::new();
-            if self.earth.shape.hit(ray, &mut record) {
-                return record.hitpoint;
+        let mut ends = vec![to_border];
+        for cloud in &self.clouds {
+            for altitude in [cloud.base - margin, cloud.top + margin] {
+                if let Some((t0, t1)) =
+                    shell(&self.earth.shape, altitude, ObjectType::Atmosphere).intersect(ray)
+                {
+                    ends.extend([t0, t1].iter().filter(|t| **t > 0. && **t < to_border));
+                }
             }
-
-            ray.org + ray.dir * (-dot * po.length())
         }
+        ends.sort_by(f64::total_cmp);
+
+        let mut start = 0.;
+        ends.iter()
+            .map(|end| {
+                let mid = ray.org + ray.dir * ((start + end) / 2.);
+                start = *end;
+                let clouds: f64 = self
+                    .clouds
+                    .iter()
+                    .filter(|c| {
+                        let top = shell(&self.earth.shape, c.top + margin, ObjectType::Atmosphere);
+                        let base =
+                            shell(&self.earth.shape, c.base - margin, ObjectType::Atmosphere);
+                        top.contains(&mid) && !base.contains(&mid)
+                    })
+                    .map(|c| c.extinction())
+                    .sum();
+                (*end, majorant + clouds)
+            })
+            .collect()
+    }
+
+    // the cloud shell is scattering at the point or it is passed through
+    pub fn cloud_shell_scatters(&self, record: &HitRecord, dir: &Vec3, rand: &mut XorRand) -> bool {
+        let Some(cloud_shell) = &self.cloud_shell else {
+            return false;
+        };
+        let (u, v) = self.earth.get_uv(&record.hitpoint);
+        let cover = self.earth.texture.get_cloud_cover(u, v).unwrap_or(0.);
+        rand.next01() >= cloud_shell.transmittance(cover, dot(*dir, record.normal))
     }
 
+    /*
+    through all the media, None if a collision happens before the border
+    the ray is bent by the refraction, the returned one is at the collision or the border
+    */
     pub fn delta_tracking(
         &self,
         ray: &Ray,
         wavelength: f64,
-        sc_type: &ScatteringType,
         rand: &mut XorRand,
-    ) -> (Option<HitRecord>, Point3) {
-        // return (hit_record, point)
-        let majorant = {
-            let coeff = self.get_atmos_coeff(&self.altitude_min_point(ray), wavelength, sc_type);
-            coeff.0 + coeff.1
-        };
-
-        let mut record = HitRecord::new();
-        let _ = self.earth.shape.hit(ray, &mut record) | self.atmosphere.hit(ray, &mut record);
-
-        let mut to_border = record.distance;
-        let mut sampled_len = -rand.next01().ln() / majorant;
-        let mut point = ray.org + sampled_len * ray.dir;
+        stats: &mut Statistics,
+    ) -> (Option<HitRecord>, Ray) {
+        // return (hit_record, ray)
+        let mut ray = Ray::new(ray.org, ray.dir);
+        let mut travelled = 0.;
+        loop {
+            let mut record = HitRecord::new();
+            let _ = self.earth.hit(&ray, &mut record) | self.atmosphere.hit(&ray, &mut record);
+            if let Some(cloud_shell) = &self.cloud_shell {
+                let shape = shell(
+                    &self.earth.shape,
+                    cloud_shell.altitude,
+                    ObjectType::CloudShell,
+                );
+                shape.hit(&ray, &mut record);
+            }
 
-        to_border -= sampled_len;
+            // a straight step of the curved ray
+            let step = self.step(&ray.org, record.distance);
+            if let Some(step) = step {
+                record.distance = step;
+            }
 
-        loop {
-            if to_border < 0. {
-                return (Some(record), point);
+            let (collision, point) = self.track(&ray, &record, wavelength, rand, stats);
+            if collision {
+                return (None, Ray::new(point, ray.dir));
             }
 
-            let ratio = {
-                let coeff = self.get_atmos_coeff(&point, wavelength, sc_type);
-                (coeff.0 + coeff.1) / majorant
-            };
+            if let Some(step) = step {
+                let mid = ray.org + ray.dir * (step / 2.);
+                ray = Ray::new(point, self.bend(&mid, &ray.dir, step, wavelength));
+                travelled += step;
+                continue;
+            }
 
-            if rand.next01() < ratio {
-                return (None, point);
+            // through the gaps of the cloud shell
+            if let ObjectType::CloudShell = record.obj_type
+                && !self.cloud_shell_scatters(&record, &ray.dir, rand)
+            {
+                ray = Ray::new(record.hitpoint + ray.dir * 0.001, ray.dir);
+                travelled += record.distance + 0.001;
+                continue;
             }
+            record.distance += travelled;
+            return (Some(record), Ray::new(point, ray.dir));
+        }
+    }
 
-            sampled_len = -rand.next01().ln() / majorant;
-            point = point + sampled_len * ray.dir;
-            to_border -= sampled_len;
+    // true and the point if a collision happens before the record
+    fn track(
+        &self,
+        ray: &Ray,
+        record: &HitRecord,
+        wavelength: f64,
+        rand: &mut XorRand,
+        stats: &mut Statistics,
+    ) -> (bool, Point3) {
+        let mut t = 0.;
+        for (end, majorant) in self.majorants(ray, record.distance, wavelength) {
+            loop {
+                // free paths are memoryless, so it starts over at the end of the segment
+                let sampled_len = if majorant > 0. {
+                    -rand.next01().ln() / majorant
+                } else {
+                    INF
+                };
+                if t + sampled_len >= end {
+                    t = end;
+                    break;
+                }
+                t += sampled_len;
+
+                let point = ray.org + t * ray.dir;
+                if rand.next01() < self.extinction(&point, wavelength) / majorant {
+                    return (true, point);
+                }
+                stats.null_collisions += 1;
+            }
         }
+
+        (false, ray.org + t * ray.dir)
     }
 
     pub fn nee(
         &self,
         org: &Point3,
         wavelength: f64,
-        sc_type: &ScatteringType,
         rand: &mut XorRand,
+        stats: &mut Statistics,
     ) -> NeeResult {
+        stats.nee_tries += 1;
         let (sample_point, pdf) = self.sun.sample(org, rand);
-        let dir = (sample_point - *org).normalize();
+        let mut dir = (sample_point - *org).normalize();
+        if self.refraction.is_some() {
+            // the sampled point keeps its offset from the apparent center of the disk
+            let center = (self.sun.center - *org).normalize();
+            dir = (dir + self.sun_direction(org, wavelength) - center).normalize();
+        }
         let ray = Ray::new(*org, dir);
         let mut record = HitRecord::new();
         record.distance = (sample_point - *org).length();
-        if self.earth.shape.hit(&ray, &mut record) {
+        if self.earth.hit(&ray, &mut record) {
             return NeeResult::new(0., 0., dir);
         }
 
-        //org is in atmosphere
-        if let (Some(_), _) = self.delta_tracking(&ray, wavelength, &sc_type, rand) {
+        //org is in atmosphere, blocked by a collision, the cloud shell
+        // or a bent ray missing the Sun
+        if let (Some(record), exit) = self.delta_tracking(&ray, wavelength, rand, stats)
+            && matches!(record.obj_type, ObjectType::Atmosphere)
+            && (self.refraction.is_none() || self.sun.hit(&exit, &mut HitRecord::new()))
+        {
             // transmittance=1
+            stats.nee_hits += 1;
             return NeeResult::new(pdf, SUN_LIGHT, dir);
         }
 
@@ -191,6 +438,54 @@ impl<'a> Scene<'a> {
     }
 }
 
+impl Hash for Scene<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.sun.hash(state);
+        self.earth.hash(state);
+        self.atmosphere.hash(state);
+        self.ocean.hash(state);
+        self.clouds.hash(state);
+        self.cloud_shell.hash(state);
+        self.refraction.hash(state);
+        self.aerosols.hash(state);
+        self.gases.hash(state);
+    }
+}
+
+// at an altitude above the Earth [km]
+fn shell(earth: &Sphere, altitude: f64, obj_type: ObjectType) -> Sphere {
+    Sphere::spheroid(
+        earth.center,
+        earth.radius + altitude,
+        earth.polar_radius + altitude,
+        earth.axis,
+        obj_type,
+    )
+}
+
+// h: altitude [km], wavelength: [nm]
+fn rayleigh_at(h: f64, wavelength: f64) -> f64 {
+    let ior = get_ior(wavelength);
+
+    let mu0 = {
+        let w_cm = wavelength * 1e-7; // [nm] -> [cm]
+        let l = 24. * PI.powi(3) / (w_cm.powi(4) * NS);
+        let m = ((ior * ior - 1.) / (ior * ior + 2.)).powi(2);
+        let r = (6. + 3. * PN) / (6. - 7. * PN);
+
+        l * m * r
+    };
+
+    let coeff = {
+        let a = 0.07771971;
+        let b = 1.16364243;
+
+        E.powf(-a * h.powf(b))
+    };
+
+    mu0 * 1e5 * coeff
+}
+
 /*
 Anthony Bucholtz. Rayleigh-scattering calculations for the terrestrial
 atmosphere. In: Applied Optics 34.15 (May 20, 1995), pp. 2765–2773.
@@ -201,3 +496,37 @@ fn get_ior(wavelength: f64) -> f64 {
     let r = 167909. / (57.362 - w_inv2);
     (l + r) * 1e-8 + 1.
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the unoccluded samples from above the subsolar point head for the Sun's disk
+    #[test]
+    fn nee_hits_the_sun() {
+        let texture = Texture::uniform(0.1);
+        let scene = Scene::new(3, &texture).unwrap();
+        let center = scene.earth.shape.center;
+        let org = center + (scene.sun.center - center).normalize() * (EARTH_RAD + 50.);
+        let mut stats = Statistics::default();
+
+        let to_center = (scene.sun.center - org).normalize();
+        let mut hits = 0;
+        let mut spread: f64 = 0.;
+        for seed in 0..100 {
+            let mut rand = XorRand::new(seed);
+            let nee = scene.nee(&org, 550., &mut rand, &mut stats);
+            if nee.pdf == 0. {
+                continue;
+            }
+            let mut record = HitRecord::new();
+            assert!(scene.sun.hit(&Ray::new(org, nee.dir), &mut record));
+            assert!(matches!(record.obj_type, ObjectType::Sun));
+            hits += 1;
+            spread = spread.max(1. - dot(nee.dir, to_center));
+        }
+        assert!(hits > 0);
+        // across the disk of about 4.65e-3 rad, not only at its center
+        assert!(spread > 1e-6);
+    }
+}
@@ -0,0 +1,124 @@
+use std::hash::{Hash, Hasher};
+
+use crate::{
+    bsdf::{Lambertian, Microfacet, Mix},
+    constant::PI,
+};
+
+// where the Earth is covered with snow or ice
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SnowCover {
+    None,
+    Mask,     // snow mask of the texture
+    Seasonal, // poleward of the snow line of the month of the scene
+}
+
+/*
+snow albedo from the grain size and the absorption of ice
+
+A. A. Kokhanovsky and E. P. Zege. Scattering optics of snow.
+In: Applied Optics 43.7 (2004), pp. 1589–1602.
+*/
+pub struct Snow {
+    pub cover: SnowCover,
+    pub month: u32,      // of the scene, 1 - 12
+    pub grain_size: f64, // optical diameter [mm], 0.1 for fresh snow, 1 for old snow
+    pub forward: f64,    // weight of the forward scattering lobe
+}
+
+impl Snow {
+    pub fn new(cover: SnowCover, month: u32) -> Self {
+        Snow {
+            cover,
+            month,
+            grain_size: 0.2,
+            forward: 0.2,
+        }
+    }
+
+    pub fn albedo(&self, wavelength: f64) -> f64 {
+        let alpha = 4. * PI * ice_absorption(wavelength) / wavelength; // [1/nm]
+        let path = 13. * self.grain_size * 1e6; // [nm]
+        (-(alpha * path).sqrt()).exp()
+    }
+
+    // diffuse reflection and a rough lobe that peaks forward at grazing sun
+    pub fn surface(&self, albedo: f64) -> Mix {
+        Mix {
+            a: Box::new(Lambertian {
+                reflectance: albedo,
+            }),
+            b: Box::new(Microfacet::constant(0.5, albedo)),
+            weight: 1. - self.forward,
+        }
+    }
+
+    pub fn covers_latitude(&self, latitude: f64) -> bool {
+        match self.cover {
+            SnowCover::Seasonal => {
+                let (north, south) = snow_line(self.month);
+                latitude > north || latitude < -south
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Hash for Snow {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.cover.hash(state);
+        self.month.hash(state);
+        self.grain_size.to_bits().hash(state);
+        self.forward.to_bits().hash(state);
+    }
+}
+
+// latitudes of the snow lines in the north and south [deg], lowest in the local winter
+fn snow_line(month: u32) -> (f64, f64) {
+    let season = (2. * PI * (month as f64 - 1.) / 12.).cos(); // 1 in January, -1 in July
+    (55. - 15. * season, 62. + 5. * season)
+}
+
+// imaginary part of the refractive index of ice, approximate values of
+// S. G. Warren and R. E. Brandt. Optical constants of ice from the ultraviolet
+// to the microwave. In: JGR 113 (2008), D14220.
+const ICE_ABSORPTION: [(f64, f64); 23] = [
+    (300., 2.0e-11),
+    (350., 1.2e-11),
+    (400., 1.6e-11),
+    (450., 5.0e-11),
+    (500., 2.5e-10),
+    (550., 9.0e-10),
+    (600., 2.3e-9),
+    (650., 1.1e-8),
+    (700., 3.1e-8),
+    (750., 6.5e-8),
+    (800., 1.3e-7),
+    (850., 3.0e-7),
+    (900., 5.0e-7),
+    (950., 1.5e-6),
+    (1000., 2.0e-6),
+    (1100., 1.7e-6),
+    (1200., 1.5e-5),
+    (1300., 1.2e-5),
+    (1400., 1.5e-4),
+    (1500., 5.5e-4),
+    (1600., 2.8e-4),
+    (1800., 1.2e-4),
+    (2000., 1.5e-3),
+];
+
+// interpolated in log scale, constant outside the table
+fn ice_absorption(wavelength: f64) -> f64 {
+    let i = ICE_ABSORPTION.partition_point(|(w, _)| *w < wavelength);
+    if i == 0 {
+        return ICE_ABSORPTION[0].1;
+    } else if i == ICE_ABSORPTION.len() {
+        return ICE_ABSORPTION[i - 1].1;
+    }
+
+    let (w0, k0) = ICE_ABSORPTION[i - 1];
+    let (w1, k1) = ICE_ABSORPTION[i];
+    let t = (wavelength - w0) / (w1 - w0);
+    (k0.ln() * (1. - t) + k1.ln() * t).exp()
+}
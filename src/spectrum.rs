@@ -1,9 +1,10 @@
-use crate::{
-    constant::E,
-    math::{Vec3, fmin},
-};
+use std::hash::{Hash, Hasher};
 
+use crate::{constant::E, math::Vec3};
+
+#[allow(clippy::upper_case_acronyms)]
 pub type XYZ = Vec3;
+#[allow(clippy::upper_case_acronyms)]
 pub type RGB = Vec3;
 
 fn gaussian(w: f64, m: f64, t1: f64, t2: f64) -> f64 {
@@ -29,6 +30,45 @@ pub fn color_matching(wavelength: f64) -> XYZ {
     Vec3(x, y, z)
 }
 
+// sensitivity of a band such as a filter or a detector, integrated over the radiance
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpectralResponse {
+    pub curve: Vec<(f64, f64)>, // (wavelength [nm] ascending, response), 0 outside
+}
+
+impl SpectralResponse {
+    pub fn new(curve: Vec<(f64, f64)>) -> Self {
+        SpectralResponse { curve }
+    }
+
+    // 1 between the wavelengths [nm]
+    pub fn band(min: f64, max: f64) -> Self {
+        SpectralResponse::new(vec![(min, 1.), (max, 1.)])
+    }
+
+    // interpolated linearly
+    pub fn eval(&self, wavelength: f64) -> f64 {
+        let i = self.curve.partition_point(|(w, _)| *w < wavelength);
+        if i == 0 || i == self.curve.len() {
+            return 0.;
+        }
+
+        let (w0, r0) = self.curve[i - 1];
+        let (w1, r1) = self.curve[i];
+        let t = (wavelength - w0) / (w1 - w0);
+        r0 * (1. - t) + r1 * t
+    }
+}
+
+impl Hash for SpectralResponse {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for (w, r) in &self.curve {
+            w.to_bits().hash(state);
+            r.to_bits().hash(state);
+        }
+    }
+}
+
 pub fn convert_to_srgb(xyz: &XYZ) -> RGB {
     let r = 3.2410 * xyz.0 + (-1.5374) * xyz.1 + (-0.4986) * xyz.2;
     let g = (-0.9692) * xyz.0 + 1.876 * xyz.1 + 0.0416 * xyz.2;
@@ -43,17 +83,74 @@ pub fn gamma(v: RGB) -> (u32, u32, u32) {
     (r, g, b)
 }
 
+// Smits, "An RGB-to-Spectrum Conversion for Reflectances" (1999)
+// 10 bins from 380 nm to 720 nm
+const SMITS_MIN: f64 = 380.;
+const SMITS_MAX: f64 = 720.;
+const SMITS_WHITE: [f64; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const SMITS_CYAN: [f64; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const SMITS_MAGENTA: [f64; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const SMITS_YELLOW: [f64; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f64; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f64; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const SMITS_BLUE: [f64; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+// linear interpolation between bin centers, constant beyond the first and last centers
+fn smits_basis(table: &[f64; 10], wavelength: f64) -> f64 {
+    let x = (wavelength - SMITS_MIN) / (SMITS_MAX - SMITS_MIN) * 10. - 0.5;
+    if x <= 0. {
+        return table[0];
+    } else if x >= 9. {
+        return table[9];
+    }
+    let i = x.floor() as usize;
+    let t = x - i as f64;
+    table[i] * (1. - t) + table[i + 1] * t
+}
+
+// linear RGB (0 - 1) to a smooth reflectance spectrum
 pub fn rgb_to_reflectance(rgb: &RGB, wavelength: f64) -> f64 {
-    let diff_r = (wavelength - 700.).abs();
-    let diff_g = (wavelength - 530.).abs();
-    let diff_b = (wavelength - 460.).abs();
-
-    let min = fmin(diff_r, fmin(diff_g, diff_b));
-    if min == diff_r {
-        rgb.0
-    } else if min == diff_g {
-        rgb.1
+    let basis = |table| smits_basis(table, wavelength);
+    let (r, g, b) = (rgb.0, rgb.1, rgb.2);
+
+    let mut value = 0.;
+    if r <= g && r <= b {
+        value += r * basis(&SMITS_WHITE);
+        if g <= b {
+            value += (g - r) * basis(&SMITS_CYAN) + (b - g) * basis(&SMITS_BLUE);
+        } else {
+            value += (b - r) * basis(&SMITS_CYAN) + (g - b) * basis(&SMITS_GREEN);
+        }
+    } else if g <= r && g <= b {
+        value += g * basis(&SMITS_WHITE);
+        if r <= b {
+            value += (r - g) * basis(&SMITS_MAGENTA) + (b - r) * basis(&SMITS_BLUE);
+        } else {
+            value += (b - g) * basis(&SMITS_MAGENTA) + (r - b) * basis(&SMITS_RED);
+        }
     } else {
-        rgb.2
+        value += b * basis(&SMITS_WHITE);
+        if r <= g {
+            value += (r - b) * basis(&SMITS_YELLOW) + (g - r) * basis(&SMITS_GREEN);
+        } else {
+            value += (g - b) * basis(&SMITS_YELLOW) + (r - g) * basis(&SMITS_RED);
+        }
     }
+
+    // reflectance must not exceed 1 to conserve energy
+    value.clamp(0., 1.)
 }
@@ -1,8 +1,14 @@
+use std::hash::{Hash, Hasher};
+
 use crate::{
-    constant::{EPS, PI},
-    math::{Point3, Vec3, cross, dot},
+    bsdf::{Bsdf, Lambertian, Microfacet, Mix, OrenNayar},
+    constant::{AXIS, EARTH_RAD, EPS, PI, WGS84_A, WGS84_F},
+    land_cover::LandCover,
+    math::{Point3, Vec3, cross, dot, fmax, to_radian},
+    ocean::Ocean,
     random::XorRand,
     ray::{HitRecord, Ray},
+    snow::{Snow, SnowCover},
     spectrum::rgb_to_reflectance,
     texture::Texture,
 };
@@ -12,34 +18,62 @@ pub enum ObjectType {
     Sun,
     Earth,
     Atmosphere,
+    CloudShell,
 }
 
+// spheroid when polar_radius differs from radius
 pub struct Sphere {
     pub center: Point3,
-    pub radius: f64,
+    pub radius: f64,       // equatorial
+    pub polar_radius: f64, // along the axis
+    pub axis: Vec3,
     pub obj_type: ObjectType,
 }
 
 impl Sphere {
     pub fn new(center: Point3, radius: f64, obj_type: ObjectType) -> Self {
+        Sphere::spheroid(center, radius, radius, Vec3(0., 0., 1.), obj_type)
+    }
+
+    pub fn spheroid(
+        center: Point3,
+        radius: f64,
+        polar_radius: f64,
+        axis: Vec3,
+        obj_type: ObjectType,
+    ) -> Self {
         Sphere {
             center,
             radius,
+            polar_radius,
+            axis,
             obj_type,
         }
     }
 
-    pub fn hit(&self, ray: &Ray, record: &mut HitRecord) -> bool {
-        let po = self.center - ray.org;
-        let b = dot(po, ray.dir);
-        let d = b * b - dot(po, po) + self.radius.powi(2);
+    // stretched along the axis so that the spheroid becomes a sphere of the equatorial radius
+    fn stretch(&self, v: Vec3) -> Vec3 {
+        v + self.axis * (dot(v, self.axis) * (self.radius / self.polar_radius - 1.))
+    }
+
+    // distances to the two intersections of the line, the nearer first
+    pub fn intersect(&self, ray: &Ray) -> Option<(f64, f64)> {
+        let po = self.stretch(self.center - ray.org);
+        let dir = self.stretch(ray.dir);
+        let a = dot(dir, dir);
+        let b = dot(po, dir);
+        let d = b * b - a * (dot(po, po) - self.radius.powi(2));
 
         if d < 0. {
-            return false;
+            return None;
         }
+        Some(((b - d.sqrt()) / a, (b + d.sqrt()) / a))
+    }
 
-        let t1 = b - d.sqrt();
-        let t2 = b + d.sqrt();
+    pub fn hit(&self, ray: &Ray, record: &mut HitRecord) -> bool {
+        let Some((t1, t2)) = self.intersect(ray) else {
+            return false;
+        };
 
         if t1 > EPS && t1 < record.distance {
             record.distance = t1;
@@ -50,11 +84,57 @@ impl Sphere {
         }
 
         record.hitpoint = ray.org + ray.dir * record.distance;
-        record.normal = (record.hitpoint - self.center) / self.radius;
+        record.normal = self.normal(&record.hitpoint);
         record.obj_type = self.obj_type;
         true
     }
 
+    pub fn contains(&self, point: &Point3) -> bool {
+        self.stretch(*point - self.center).length() < self.radius
+    }
+
+    // outward normal of the spheroid through the point
+    pub fn normal(&self, point: &Point3) -> Vec3 {
+        let p = *point - self.center;
+        let k = (self.radius / self.polar_radius).powi(2) - 1.;
+        (p + self.axis * (dot(p, self.axis) * k)).normalize()
+    }
+
+    // distance from the center to the surface, cos_axis: cosine of the angle to the axis
+    pub fn surface_radius(&self, cos_axis: f64) -> f64 {
+        let sin2 = 1. - cos_axis * cos_axis;
+        1. / (sin2 / self.radius.powi(2) + cos_axis * cos_axis / self.polar_radius.powi(2)).sqrt()
+    }
+
+    // height above the surface along the direction from the center
+    pub fn altitude(&self, point: &Point3) -> f64 {
+        let p = *point - self.center;
+        let l = p.length();
+        l - self.surface_radius(dot(p, self.axis) / l)
+    }
+
+    /*
+    lower bound of the altitude along the ray, 0 if it hits the surface
+
+    h = (|s| - a) * r / a for a point s in the stretched space, where r is the distance
+    from the center to the surface in its direction, and b <= r <= a
+    */
+    pub fn altitude_min(&self, ray: &Ray) -> f64 {
+        let o = self.stretch(ray.org - self.center);
+        let dir = self.stretch(ray.dir);
+        let t = fmax(-dot(o, dir) / dot(dir, dir), 0.);
+        fmax(
+            ((o + dir * t).length() - self.radius) * self.polar_radius / self.radius,
+            0.,
+        )
+    }
+
+    // (distance from the axis, distance along the axis) of a point at a geodetic latitude [rad]
+    pub fn meridian(&self, latitude: f64, altitude: f64) -> (f64, f64) {
+        meridian(self.radius, self.polar_radius, latitude, altitude)
+    }
+
+    // a point on the sphere in the cone seen from org, pdf per solid angle
     pub fn sample(&self, org: &Point3, rand: &mut XorRand) -> (Point3, f64) {
         let po = self.center - *org;
         let cos_mu = (1. - (self.radius * self.radius / po.length_sq())).sqrt();
@@ -73,7 +153,12 @@ impl Sphere {
 
         let dir = u * sin_theta * phi.cos() + v * sin_theta * phi.sin() + w * cos_theta;
         let pdf = 1. / (2. * PI * (1. - cos_mu));
-        (dir, pdf)
+        // the nearer intersection, the closest approach at the rim
+        let t = match self.intersect(&Ray::new(*org, dir)) {
+            Some((t, _)) => t,
+            None => dot(po, dir),
+        };
+        (*org + dir * t, pdf)
     }
 
     pub fn pdf_sampling(&self, org: &Point3) -> f64 {
@@ -82,32 +167,119 @@ impl Sphere {
     }
 }
 
+impl Hash for Sphere {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.center.hash(state);
+        self.radius.to_bits().hash(state);
+        self.polar_radius.to_bits().hash(state);
+        self.axis.hash(state);
+    }
+}
+
+// (distance from the axis, distance along the axis) of a point at a geodetic latitude [rad]
+pub fn meridian(radius: f64, polar_radius: f64, latitude: f64, altitude: f64) -> (f64, f64) {
+    let e2 = 1. - (polar_radius / radius).powi(2);
+    // radius of curvature in the prime vertical
+    let n = radius / (1. - e2 * latitude.sin().powi(2)).sqrt();
+    (
+        (n + altitude) * latitude.cos(),
+        (n * (1. - e2) + altitude) * latitude.sin(),
+    )
+}
+
+// rotation axis of the Earth, toward the north pole
+pub fn earth_axis() -> Vec3 {
+    Vec3(0., to_radian(AXIS + 90.).cos(), to_radian(AXIS + 90.).sin())
+}
+
+// shape of the Earth, latitudes of the textures and the eye are geodetic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Figure {
+    Sphere, // radius EARTH_RAD
+    Wgs84,  // oblate ellipsoid of the World Geodetic System 1984
+}
+
+impl Figure {
+    pub const ALL: [Figure; 2] = [Figure::Sphere, Figure::Wgs84];
+
+    // equatorial and polar radii [km]
+    pub fn semi_axes(&self) -> (f64, f64) {
+        match self {
+            Figure::Sphere => (EARTH_RAD, EARTH_RAD),
+            Figure::Wgs84 => (WGS84_A, WGS84_A * (1. - WGS84_F)),
+        }
+    }
+}
+
+// texels with a specular value at or above this are the sea
+const OCEAN_SPECULAR: u8 = 120;
+
 pub struct Earth<'a> {
     pub shape: Sphere,
+    figure: Figure,
     pub texture: &'a Texture,
+    pub snow: Snow,
     pub u: Vec3,
     pub v: Vec3,
     pub w: Vec3,
 }
 
 impl<'a> Earth<'a> {
-    pub fn new(shape: Sphere, texture: &'a Texture) -> Self {
+    pub fn new(center: Point3, figure: Figure, texture: &'a Texture, month: u32) -> Self {
+        let (radius, polar_radius) = figure.semi_axes();
         Earth {
-            shape,
+            shape: Sphere::spheroid(
+                center,
+                radius,
+                polar_radius,
+                earth_axis(),
+                ObjectType::Earth,
+            ),
+            figure,
             texture,
+            snow: Snow::new(
+                if texture.snow_mask.is_some() {
+                    SnowCover::Mask
+                } else {
+                    SnowCover::None
+                },
+                month,
+            ),
             u: Vec3::zero(),
             v: Vec3::zero(),
             w: Vec3::zero(),
         }
     }
 
+    pub fn figure(&self) -> Figure {
+        self.figure
+    }
+
+    pub fn set_figure(&mut self, figure: Figure) {
+        let (radius, polar_radius) = figure.semi_axes();
+        self.shape.radius = radius;
+        self.shape.polar_radius = polar_radius;
+        self.figure = figure;
+    }
+
+    // the sphere displaced by the elevation model if there is one
+    pub fn hit(&self, ray: &Ray, record: &mut HitRecord) -> bool {
+        let is_hit = match &self.texture.elevation {
+            Some(terrain) => terrain.hit(self, ray, record),
+            None => false,
+        };
+        // under the terrain, and the poles not covered by it
+        self.shape.hit(ray, record) | is_hit
+    }
+
+    // v is the geodetic colatitude, the direction of the normal
     pub fn get_uv(&self, point: &Point3) -> (f64, f64) {
-        let op = (*point - self.shape.center).normalize();
-        let theta = dot(self.w, op).acos(); // 0 - PI
+        let op = self.shape.normal(point);
+        let theta = dot(self.w, op).clamp(-1., 1.).acos(); // 0 - PI
         let u_op = dot(self.u, op);
 
         let mut phi; // 0 - 2PI
-        if u_op < EPS && u_op >= 0. {
+        if (0. ..EPS).contains(&u_op) {
             phi = PI / 2.;
         } else if u_op > -EPS && u_op < 0. {
             phi = 3. * PI / 2.
@@ -116,29 +288,93 @@ impl<'a> Earth<'a> {
         } else {
             phi = (dot(self.v, op) / u_op).atan();
             let cos_phi = u_op / theta.sin();
-            if cos_phi < 0. && phi < 0. {
-                phi = PI + phi;
-            } else if cos_phi < 0. && phi > 0. {
-                phi = PI + phi;
-            } else if cos_phi > 0. && phi < 0. {
-                phi = 2. * PI + phi;
+            if cos_phi < 0. {
+                phi += PI;
+            } else if phi < 0. {
+                phi += 2. * PI;
             }
         }
 
         (phi / (2. * PI), theta / PI)
     }
 
-    pub fn get_reflectance(&self, point: &Point3, wavelength: f64) -> f64 {
+    // footprint: width of the ray on the surface [km]
+    pub fn get_reflectance(&self, point: &Point3, wavelength: f64, footprint: f64) -> f64 {
         let (u, v) = self.get_uv(point);
-        let rgb = self.texture.get_rgb(u, v);
+        self.get_reflectance_uv(u, v, wavelength, footprint)
+    }
+
+    fn get_reflectance_uv(&self, u: f64, v: f64, wavelength: f64, footprint: f64) -> f64 {
+        if let Some(reflectance) = self.texture.get_class_reflectance(u, v, wavelength) {
+            return reflectance;
+        }
+        let rgb = self.texture.get_rgb(u, v, footprint / (PI * EARTH_RAD));
 
         rgb_to_reflectance(&rgb, wavelength)
     }
 
-    pub fn get_property(&self, point: &Point3, u: f64, v: f64) -> (u8, Vec3) {
-        let (reflectance, normal) = self.texture.get_property(u, v);
+    // specular: from get_property(), used when there is no land cover map
+    pub fn get_material(
+        &self,
+        u: f64,
+        v: f64,
+        specular: u8,
+        wavelength: f64,
+        footprint: f64,
+        ocean: &Ocean,
+    ) -> Box<dyn Bsdf> {
+        let reflectance = self.get_reflectance_uv(u, v, wavelength, footprint);
+        let ocean = || Box::new(ocean.surface(reflectance, wavelength));
 
-        let z = (*point - self.shape.center).normalize();
+        match self.texture.get_class(u, v) {
+            Some(LandCover::Water) => ocean(),
+            Some(LandCover::Forest) => Box::new(OrenNayar {
+                reflectance,
+                sigma: 0.5,
+            }),
+            Some(LandCover::Grassland) => Box::new(OrenNayar {
+                reflectance,
+                sigma: 0.3,
+            }),
+            Some(LandCover::Desert) => Box::new(OrenNayar {
+                reflectance,
+                sigma: 0.25,
+            }),
+            Some(LandCover::SnowIce) => Box::new(self.snow.surface(reflectance)),
+            // concrete and roofs with a weak sheen
+            Some(LandCover::Urban) => Box::new(Mix {
+                a: Box::new(Lambertian { reflectance }),
+                b: Box::new(Microfacet::dielectric(0.2, 1.5)),
+                weight: 0.9,
+            }),
+            None if self.is_snow(u, v) => Box::new(self.snow.surface(self.snow.albedo(wavelength))),
+            None if specular >= OCEAN_SPECULAR => ocean(),
+            None => Box::new(Lambertian { reflectance }),
+        }
+    }
+
+    // snow or sea ice by the mask or the season
+    fn is_snow(&self, u: f64, v: f64) -> bool {
+        match self.snow.cover {
+            SnowCover::None => false,
+            SnowCover::Mask => self.texture.get_snow(u, v).unwrap_or(false),
+            SnowCover::Seasonal => self.snow.covers_latitude(90. - v * 180.),
+        }
+    }
+
+    // surface_normal: outward geometric normal at the hit, of the terrain or the sphere
+    pub fn get_property(
+        &self,
+        surface_normal: &Vec3,
+        u: f64,
+        v: f64,
+        footprint: f64,
+    ) -> (u8, Vec3) {
+        let (reflectance, normal) = self
+            .texture
+            .get_property(u, v, footprint / (PI * EARTH_RAD));
+
+        let z = *surface_normal;
         let x = cross(self.w, z).normalize();
         let y = cross(x, z);
 
@@ -146,3 +382,37 @@ impl<'a> Earth<'a> {
         (reflectance, normal)
     }
 }
+
+impl Hash for Earth<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.shape.hash(state);
+        self.figure.hash(state);
+        self.texture.hash(state);
+        self.snow.hash(state);
+        self.u.hash(state);
+        self.v.hash(state);
+        self.w.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the samples are points on the surface, reached first along their direction
+    #[test]
+    fn sample_on_the_surface() {
+        let sphere = Sphere::new(Vec3::zero(), 10., ObjectType::Sun);
+        let org = Vec3(100., 20., -30.);
+        let mut rand = XorRand::new(1);
+        for _ in 0..100 {
+            let (point, pdf) = sphere.sample(&org, &mut rand);
+            assert!(((point - sphere.center).length() - sphere.radius).abs() < 1e-6);
+            assert_eq!(pdf, sphere.pdf_sampling(&org));
+
+            let mut record = HitRecord::new();
+            assert!(sphere.hit(&Ray::new(org, (point - org).normalize()), &mut record));
+            assert!((record.distance - (point - org).length()).abs() < 1e-6);
+        }
+    }
+}
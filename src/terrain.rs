@@ -0,0 +1,534 @@
+use std::hash::{Hash, Hasher};
+
+use crate::{
+    constant::{EPS, PI},
+    math::{Point3, Vec3, cross, dot},
+    ray::{HitRecord, Ray},
+    sphere::{Earth, Figure, ObjectType, meridian},
+    texture::Map,
+};
+
+// each level pops one node and pushes at most 4 children,
+// so the traversal holds at most 1 + 3 * (levels - 1) nodes
+const STACK_SIZE: usize = 64;
+
+// [texels] the widest and highest map whose quadtree fits the stack, 22 levels
+pub const MAX_SIZE: usize = 1 << 21;
+
+// min/max heights of the cells of one level, a cell lies between 4 texel centers
+struct Level {
+    width: usize,
+    height: usize,
+    bounds: Vec<(u16, u16)>,
+    // cos and sin of the longitude of the node centers
+    cols: Vec<(f64, f64)>,
+    rows: Vec<Row>,
+}
+
+// the nodes of a row are the same up to the rotation about the axis
+struct Row {
+    sin_theta: f64, // colatitude of the node centers
+    cos_theta: f64,
+    // distance from the normal at the center to the farthest one, negative to always visit
+    spread: f64,
+    bases: [Base; 2], // by Figure
+}
+
+// the surface under the nodes of a row
+#[derive(Clone, Copy, Default)]
+struct Base {
+    rho: f64,    // distance of the center from the axis
+    z: f64,      // along the axis
+    spread: f64, // distance from the center to the farthest point
+    radius: f64, // distance of the farthest point from the center of the Earth
+}
+
+/*
+digital elevation model displacing the Earth's sphere or spheroid along its normal
+
+the heights are texels of an equirectangular 16-bit map, heights below the sea are clipped to 0
+rays are intersected with the two triangles of each cell by traversing the min/max quadtree,
+a node is skipped if the ray misses its bounding sphere or passes above its highest point
+everything is computed in the frame of the Earth (u, v, w) with the origin at its center
+*/
+pub struct Terrain {
+    heights: Map<u16>,
+    levels: Vec<Level>,          // the finest first
+    cols: Vec<(f64, f64)>,       // cos and sin of the longitude of the texels
+    rows: Vec<(f64, f64)>,       // sin and cos of the colatitude of the texels
+    bases: [Vec<(f64, f64)>; 2], // (distance from the axis, along the axis) of the texels by Figure
+    pub scale: f64,              // [km] per unit, 0.001 for heights in meters
+}
+
+impl Terrain {
+    pub fn new(heights: Map<u16>) -> Self {
+        let width = heights.width;
+        let height = heights.height.saturating_sub(1).max(1);
+        let texel =
+            |x: usize, y: usize| heights.texel(x % heights.width, y.min(heights.height - 1))[0];
+
+        let mut bounds = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let h = [
+                    texel(x, y),
+                    texel(x + 1, y),
+                    texel(x, y + 1),
+                    texel(x + 1, y + 1),
+                ];
+                bounds.push((*h.iter().min().unwrap(), *h.iter().max().unwrap()));
+            }
+        }
+        let mut levels = vec![Level::new(&heights, 0, width, height, bounds)];
+
+        while levels.last().unwrap().width > 1 || levels.last().unwrap().height > 1 {
+            let prev = levels.last().unwrap();
+            let width = prev.width.div_ceil(2);
+            let height = prev.height.div_ceil(2);
+            let mut bounds = Vec::with_capacity(width * height);
+            for y in 0..height {
+                for x in 0..width {
+                    let mut b = (u16::MAX, 0);
+                    for (cx, cy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let (px, py) = (2 * x + cx, 2 * y + cy);
+                        if px < prev.width && py < prev.height {
+                            let c = prev.bounds[py * prev.width + px];
+                            b = (b.0.min(c.0), b.1.max(c.1));
+                        }
+                    }
+                    bounds.push(b);
+                }
+            }
+            let level = Level::new(&heights, levels.len(), width, height, bounds);
+            levels.push(level);
+        }
+        assert!(
+            3 * (levels.len() - 1) < STACK_SIZE,
+            "elevation model larger than {MAX_SIZE} texels"
+        );
+
+        let cols = (0..heights.width)
+            .map(|x| {
+                let phi = longitude(&heights, x as f64);
+                (phi.cos(), phi.sin())
+            })
+            .collect();
+        let rows = (0..heights.height)
+            .map(|y| {
+                let theta = colatitude(&heights, y as f64);
+                (theta.sin(), theta.cos())
+            })
+            .collect();
+        let bases = Figure::ALL.map(|figure| {
+            (0..heights.height)
+                .map(|y| base(figure, colatitude(&heights, y as f64)))
+                .collect()
+        });
+
+        Terrain {
+            heights,
+            levels,
+            cols,
+            rows,
+            bases,
+            scale: 0.001,
+        }
+    }
+
+    // [km]
+    pub fn max_height(&self) -> f64 {
+        self.levels.last().unwrap().bounds[0].1 as f64 * self.scale
+    }
+
+    // x wraps around and y is clamped
+    fn vertex(&self, figure: usize, x: usize, y: usize) -> Point3 {
+        let x = x % self.heights.width;
+        let y = y.min(self.heights.height - 1);
+        let h = self.heights.texel(x, y)[0] as f64 * self.scale;
+        let (cos_phi, sin_phi) = self.cols[x];
+        let (sin_theta, cos_theta) = self.rows[y];
+        let (rho, z) = self.bases[figure][y];
+        let rho = rho + h * sin_theta;
+        Vec3(rho * cos_phi, rho * sin_phi, z + h * cos_theta)
+    }
+
+    // distance to enter the node, None if the ray misses it before max_t
+    fn enter_node(
+        &self,
+        ray: &Ray,
+        figure: usize,
+        level: usize,
+        i: usize,
+        j: usize,
+        max_t: f64,
+    ) -> Option<f64> {
+        let lv = &self.levels[level];
+        let row = &lv.rows[j];
+        if row.spread < 0. {
+            return Some(0.);
+        }
+
+        let (min, max) = lv.bounds[j * lv.width + i];
+        let h0 = min as f64 * self.scale;
+        let h1 = max as f64 * self.scale;
+        let hm = (h0 + h1) / 2.;
+        let base = &row.bases[figure];
+        let bound = base.spread + hm * row.spread + (h1 - h0) / 2. + EPS;
+
+        let (cos_phi, sin_phi) = lv.cols[i];
+        let rho = base.rho + hm * row.sin_theta;
+        let center = Vec3(rho * cos_phi, rho * sin_phi, base.z + hm * row.cos_theta);
+        let (t0, t1) = sphere_interval(ray, &center, bound)?;
+        if t1 < EPS || t0 >= max_t {
+            return None;
+        }
+        let (t0, t1) = (t0.max(0.), t1.min(max_t));
+
+        // closest approach to the center of the Earth in the node
+        let t = (-dot(ray.org, ray.dir)).clamp(t0, t1);
+        if (ray.org + ray.dir * t).length() > base.radius + h1 {
+            return None;
+        }
+        Some(t0)
+    }
+
+    pub fn hit(&self, earth: &Earth, ray: &Ray, record: &mut HitRecord) -> bool {
+        let figure = earth.figure() as usize;
+        let o = ray.org - earth.shape.center;
+        let local = Ray::new(
+            Vec3(dot(o, earth.u), dot(o, earth.v), dot(o, earth.w)),
+            Vec3(
+                dot(ray.dir, earth.u),
+                dot(ray.dir, earth.v),
+                dot(ray.dir, earth.w),
+            ),
+        );
+
+        let outer = earth.shape.radius + self.max_height();
+        match sphere_interval(&local, &Vec3::zero(), outer) {
+            Some((t0, t1)) if t1 > EPS && t0 < record.distance => {}
+            _ => return false,
+        }
+
+        let mut hit: Option<(f64, Vec3)> = None;
+        let mut max_t = record.distance;
+        let top = self.levels.len() - 1;
+        let mut stack = [(0, 0, 0, 0.); STACK_SIZE];
+        stack[0] = (top, 0, 0, 0.);
+        let mut sp = 1;
+
+        while sp > 0 {
+            sp -= 1;
+            let (level, i, j, t) = stack[sp];
+            if t >= max_t {
+                continue;
+            }
+
+            if level == 0 {
+                if let Some((t, normal)) = self.hit_cell(&local, figure, i, j, max_t) {
+                    hit = Some((t, normal));
+                    max_t = t;
+                }
+                continue;
+            }
+
+            // children sorted from far to near, so that the nearest is popped first
+            let mut children = [(0, 0, 0.); 4];
+            let mut n = 0;
+            let child = &self.levels[level - 1];
+            for (ci, cj) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let (x, y) = (2 * i + ci, 2 * j + cj);
+                if x >= child.width || y >= child.height {
+                    continue;
+                }
+                if let Some(t) = self.enter_node(&local, figure, level - 1, x, y, max_t) {
+                    children[n] = (x, y, t);
+                    n += 1;
+                }
+            }
+            children[..n].sort_by(|a, b| b.2.total_cmp(&a.2));
+            for (x, y, t) in &children[..n] {
+                stack[sp] = (level - 1, *x, *y, *t);
+                sp += 1;
+            }
+        }
+
+        let Some((t, normal)) = hit else {
+            return false;
+        };
+        record.distance = t;
+        record.hitpoint = ray.org + ray.dir * t;
+        record.normal = earth.u * normal.0 + earth.v * normal.1 + earth.w * normal.2;
+        record.obj_type = ObjectType::Earth;
+        true
+    }
+
+    // distance and the outward normal in the frame of the Earth
+    fn hit_cell(
+        &self,
+        ray: &Ray,
+        figure: usize,
+        x: usize,
+        y: usize,
+        max_t: f64,
+    ) -> Option<(f64, Vec3)> {
+        let p00 = self.vertex(figure, x, y);
+        let p10 = self.vertex(figure, x + 1, y);
+        let p01 = self.vertex(figure, x, y + 1);
+        let p11 = self.vertex(figure, x + 1, y + 1);
+
+        let mut hit = None;
+        let mut max_t = max_t;
+        for (a, b, c) in [(p00, p10, p11), (p00, p11, p01)] {
+            let Some(t) = hit_triangle(ray, &a, &b, &c) else {
+                continue;
+            };
+            if t >= max_t {
+                continue;
+            }
+
+            let mut normal = cross(b - a, c - a).normalize();
+            if dot(normal, a) < 0. {
+                normal = -normal;
+            }
+            hit = Some((t, normal));
+            max_t = t;
+        }
+        hit
+    }
+}
+
+impl Level {
+    fn new(
+        heights: &Map<u16>,
+        level: usize,
+        width: usize,
+        height: usize,
+        bounds: Vec<(u16, u16)>,
+    ) -> Self {
+        // nodes are 2^level cells wide, the last column may wrap around
+        let span = (1 << level) as f64;
+        let cols = (0..width)
+            .map(|i| {
+                let phi = longitude(heights, i as f64 * span + span / 2.);
+                (phi.cos(), phi.sin())
+            })
+            .collect();
+
+        let rows = (0..height)
+            .map(|j| {
+                let y0 = j as f64 * span;
+                let y1 = (y0 + span).min((heights.height - 1).max(1) as f64);
+                let ym = (y0 + y1) / 2.;
+                let theta = colatitude(heights, ym);
+
+                // nodes too large to be bounded tightly are always visited
+                if span / heights.width as f64 > 0.25 || (y1 - y0) / heights.height as f64 > 0.25 {
+                    return Row {
+                        sin_theta: theta.sin(),
+                        cos_theta: theta.cos(),
+                        spread: -1.,
+                        bases: [Base::default(); 2],
+                    };
+                }
+
+                let samples = [
+                    (0., y0),
+                    (span, y0),
+                    (0., y1),
+                    (span, y1),
+                    (span / 2., y0),
+                    (span / 2., y1),
+                    (0., ym),
+                    (span, ym),
+                ];
+                // margin for the edges between the sampled points
+                let spread = |point: &dyn Fn(f64, f64) -> Vec3| {
+                    let center = point(span / 2., ym);
+                    samples
+                        .iter()
+                        .map(|(x, y)| (point(*x, *y) - center).length())
+                        .fold(0., f64::max)
+                        * 1.05
+                };
+                let rotate = |x: f64, (rho, z): (f64, f64)| {
+                    let phi = longitude(heights, x);
+                    Vec3(rho * phi.cos(), rho * phi.sin(), z)
+                };
+
+                let normal = |x: f64, y: f64| {
+                    let theta = colatitude(heights, y);
+                    rotate(x, (theta.sin(), theta.cos()))
+                };
+                let bases = Figure::ALL.map(|figure| {
+                    let (rho, z) = base(figure, theta);
+                    let point = |x: f64, y: f64| rotate(x, base(figure, colatitude(heights, y)));
+
+                    // the surface is farthest from the center at the equator
+                    let (t0, t1) = (colatitude(heights, y0), colatitude(heights, y1));
+                    let radius = if t0 <= PI / 2. && PI / 2. <= t1 {
+                        figure.semi_axes().0
+                    } else {
+                        point(0., y0).length().max(point(0., y1).length())
+                    };
+
+                    Base {
+                        rho,
+                        z,
+                        spread: spread(&point),
+                        radius,
+                    }
+                });
+
+                Row {
+                    sin_theta: theta.sin(),
+                    cos_theta: theta.cos(),
+                    spread: spread(&normal),
+                    bases,
+                }
+            })
+            .collect();
+
+        Level {
+            width,
+            height,
+            bounds,
+            cols,
+            rows,
+        }
+    }
+}
+
+impl Hash for Terrain {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.heights.hash(state);
+        self.scale.to_bits().hash(state);
+    }
+}
+
+// point of the surface at a colatitude, (distance from the axis, along the axis)
+fn base(figure: Figure, theta: f64) -> (f64, f64) {
+    let (radius, polar_radius) = figure.semi_axes();
+    meridian(radius, polar_radius, PI / 2. - theta, 0.)
+}
+
+// texel centers, x can exceed the width
+fn longitude(heights: &Map<u16>, x: f64) -> f64 {
+    2. * PI * (x + 0.5) / heights.width as f64
+}
+
+fn colatitude(heights: &Map<u16>, y: f64) -> f64 {
+    PI * ((y + 0.5) / heights.height as f64).min(1.)
+}
+
+fn sphere_interval(ray: &Ray, center: &Point3, radius: f64) -> Option<(f64, f64)> {
+    let po = *center - ray.org;
+    let b = dot(po, ray.dir);
+    let d = b * b - dot(po, po) + radius * radius;
+    if d < 0. {
+        return None;
+    }
+    Some((b - d.sqrt(), b + d.sqrt()))
+}
+
+// Möller–Trumbore
+fn hit_triangle(ray: &Ray, a: &Point3, b: &Point3, c: &Point3) -> Option<f64> {
+    let e1 = *b - *a;
+    let e2 = *c - *a;
+    let p = cross(ray.dir, e2);
+    let det = dot(e1, p);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+
+    let s = ray.org - *a;
+    let u = dot(s, p) / det;
+    if !(0. ..=1.).contains(&u) {
+        return None;
+    }
+    let q = cross(s, e1);
+    let v = dot(ray.dir, q) / det;
+    if v < 0. || u + v > 1. {
+        return None;
+    }
+
+    let t = dot(e2, q) / det;
+    if t > EPS { Some(t) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{constant::EARTH_RAD, texture::Texture};
+
+    fn earth(texture: &Texture) -> Earth<'_> {
+        let mut earth = Earth::new(Vec3::zero(), Figure::Sphere, texture, 1);
+        (earth.u, earth.v, earth.w) = (Vec3(1., 0., 0.), Vec3(0., 1., 0.), Vec3(0., 0., 1.));
+        earth
+    }
+
+    fn terrain(width: usize, height: usize, heights: impl Fn(usize, usize) -> u16) -> Terrain {
+        let data = (0..width * height)
+            .map(|i| heights(i % width, i / width))
+            .collect();
+        Terrain::new(Map {
+            data,
+            channels: 1,
+            width,
+            height,
+        })
+    }
+
+    fn hit(terrain: &Terrain, ray: &Ray) -> Option<HitRecord> {
+        let texture = Texture::uniform(0.1);
+        let mut record = HitRecord::new();
+        terrain
+            .hit(&earth(&texture), ray, &mut record)
+            .then_some(record)
+    }
+
+    // the cells are flat, so the hits are under the sphere by at most their sagitta
+    #[test]
+    fn flat_dem() {
+        let terrain = terrain(512, 256, |_, _| 1000);
+        let r = EARTH_RAD + 1.;
+        for (x, y) in [(0.3, 0.1), (-0.5, 0.7), (0.2, -0.9), (0., 0.)] {
+            let dir = Vec3(-1., x, y).normalize();
+            let org = Vec3(EARTH_RAD + 500., 0., 0.);
+            let ray = Ray::new(org, dir);
+            let (t0, _) = sphere_interval(&ray, &Vec3::zero(), r).unwrap();
+
+            let record = hit(&terrain, &ray).unwrap();
+            let radius = record.hitpoint.length();
+            assert!(record.distance > t0 - 1e-6 && radius < r + 1e-6 && radius > r - 0.3);
+            assert!(dot(record.normal, record.hitpoint.normalize()) > 0.99);
+        }
+
+        // above the terrain
+        let ray = Ray::new(Vec3(r + 0.1, -2000., 0.), Vec3(0., 1., 0.));
+        assert!(hit(&terrain, &ray).is_none());
+    }
+
+    // one texel of 8 km on the equator, the rest at the sea level
+    #[test]
+    fn single_peak_dem() {
+        let terrain = terrain(64, 33, |x, y| if (x, y) == (16, 16) { 8000 } else { 0 });
+        let peak = longitude(&terrain.heights, 16.);
+        let up = Vec3(peak.cos(), peak.sin(), 0.);
+        let east = Vec3(-peak.sin(), peak.cos(), 0.);
+
+        // from above, close to the summit
+        let org = up * (EARTH_RAD + 100.) + Vec3(0., 0., 1.);
+        let record = hit(&terrain, &Ray::new(org, -up)).unwrap();
+        assert!(record.hitpoint.length() > EARTH_RAD + 7.);
+
+        // grazing at 4 km, through the peak and beside it
+        let org = up * (EARTH_RAD + 4.) - east * 1000.;
+        let record = hit(&terrain, &Ray::new(org, east)).unwrap();
+        assert!(record.distance < 1000. && record.hitpoint.length() < EARTH_RAD + 8.);
+        assert!(dot(record.normal, east) < 0.);
+
+        let org = up * (EARTH_RAD + 4.) - east * 1000. + Vec3(0., 0., 500.);
+        assert!(hit(&terrain, &Ray::new(org, east)).is_none());
+    }
+}
@@ -1,50 +1,349 @@
-use std::{fs::File, io::BufReader};
+use std::{
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{BufReader, Read},
+    path::Path,
+};
 
-use tiff::decoder::{Decoder, DecodingResult};
+use tiff::{
+    ColorType,
+    decoder::{Decoder, DecodingResult},
+};
 use zune_jpeg::JpegDecoder;
 
-use crate::{math::Vec3, spectrum::RGB};
+use crate::{
+    error::{Error, Result},
+    land_cover::{LandCover, SpectralLibrary},
+    math::Vec3,
+    spectrum::RGB,
+    terrain::{MAX_SIZE, Terrain},
+};
 
-pub struct Texture {
-    pub rgb_data: Vec<RGB>,
-    pub specular_data: Option<Vec<u8>>,
-    pub normal_data: Option<Vec<Vec3>>,
+// equirectangular image, u (longitude) wraps around and v (latitude) is clamped
+#[derive(Hash)]
+pub struct Map<T> {
+    pub data: Vec<T>,
+    pub channels: usize,
     pub width: usize,
     pub height: usize,
 }
 
-impl Texture {
-    pub fn new() -> Self {
-        let (rgb_data, width, height) = load_jpg();
-        let (specular_data, normal_data) = load_tiff(width, height);
-        Texture {
-            rgb_data,
-            specular_data,
-            normal_data,
+impl<T: Copy> Map<T> {
+    pub fn texel(&self, x: usize, y: usize) -> &[T] {
+        let id = (y * self.width + x) * self.channels;
+        &self.data[id..id + self.channels]
+    }
+
+    pub fn nearest(&self, u: f64, v: f64) -> &[T] {
+        let x = (u.rem_euclid(1.) * self.width as f64) as usize;
+        let y = (v.clamp(0., 1.) * self.height as f64) as usize;
+        self.texel(x.min(self.width - 1), y.min(self.height - 1))
+    }
+}
+
+impl Map<u8> {
+    // texel centers are at (x + 0.5) / width, up to 4 channels
+    pub fn bilinear(&self, u: f64, v: f64, decode: &impl Fn(u8) -> f64) -> [f64; 4] {
+        let x = u.rem_euclid(1.) * self.width as f64 - 0.5;
+        let y = (v.clamp(0., 1.) * self.height as f64 - 0.5).clamp(0., (self.height - 1) as f64);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let xs = [
+            (x0 as isize).rem_euclid(self.width as isize) as usize,
+            (x0 as isize + 1).rem_euclid(self.width as isize) as usize,
+        ];
+        let ys = [y0 as usize, (y0 as usize + 1).min(self.height - 1)];
+        let weights = [
+            (1. - fx) * (1. - fy),
+            fx * (1. - fy),
+            (1. - fx) * fy,
+            fx * fy,
+        ];
+
+        let mut result = [0.; 4];
+        for (i, w) in weights.iter().enumerate() {
+            let t = self.texel(xs[i % 2], ys[i / 2]);
+            for c in 0..self.channels.min(4) {
+                result[c] += w * decode(t[c]);
+            }
+        }
+        result
+    }
+
+    // half resolution, averaged in the decoded space
+    fn downsample(&self, decode: &impl Fn(u8) -> f64, encode: &impl Fn(f64) -> u8) -> Map<u8> {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut data = Vec::with_capacity(width * height * self.channels);
+        for y in 0..height {
+            for x in 0..width {
+                let xs = [(2 * x).min(self.width - 1), (2 * x + 1).min(self.width - 1)];
+                let ys = [
+                    (2 * y).min(self.height - 1),
+                    (2 * y + 1).min(self.height - 1),
+                ];
+                for c in 0..self.channels {
+                    let sum: f64 = ys
+                        .iter()
+                        .flat_map(|y| xs.iter().map(move |x| (*x, *y)))
+                        .map(|(x, y)| decode(self.texel(x, y)[c]))
+                        .sum();
+                    data.push(encode(sum / 4.));
+                }
+            }
+        }
+
+        Map {
+            data,
+            channels: self.channels,
             width,
             height,
         }
     }
+}
+
+// pyramid of a map down to 1 texel in height
+#[derive(Hash)]
+pub struct MipMap {
+    pub levels: Vec<Map<u8>>,
+}
+
+impl MipMap {
+    pub fn new(base: Map<u8>, decode: &impl Fn(u8) -> f64, encode: &impl Fn(f64) -> u8) -> Self {
+        let mut levels = vec![base];
+        while levels.last().unwrap().height > 1 {
+            let next = levels.last().unwrap().downsample(decode, encode);
+            levels.push(next);
+        }
+        MipMap { levels }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.levels[0].channels
+    }
+
+    // footprint: width of the filter in v, 0 for bilinear filtering of the finest level
+    pub fn sample(&self, u: f64, v: f64, footprint: f64, decode: &impl Fn(u8) -> f64) -> [f64; 4] {
+        let texels = footprint * self.levels[0].height as f64;
+        if texels <= 1. || self.levels.len() == 1 {
+            return self.levels[0].bilinear(u, v, decode);
+        }
+
+        let lod = texels.log2().min((self.levels.len() - 1) as f64);
+        let l0 = lod.floor() as usize;
+        let l1 = (l0 + 1).min(self.levels.len() - 1);
+        let t = lod - l0 as f64;
+
+        let a = self.levels[l0].bilinear(u, v, decode);
+        let b = self.levels[l1].bilinear(u, v, decode);
+        [0, 1, 2, 3].map(|c| a[c] * (1. - t) + b[c] * t)
+    }
+}
+
+impl Map<u16> {
+    fn to_u8(&self) -> Map<u8> {
+        Map {
+            data: self.data.iter().map(|v| (v >> 8) as u8).collect(),
+            channels: self.channels,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+// maps can have different resolutions, None if not used
+#[derive(Debug, Clone, Default)]
+pub struct TexturePaths {
+    pub albedo: Option<String>,
+    pub specular: Option<String>,
+    pub normal: Option<String>,
+    pub land_cover: Option<String>, // 8-bit 1 channel, index of LandCover
+    pub spectral_library: Option<String>, // CSV, required with land_cover
+    pub snow_mask: Option<String>,  // 1 channel, snow or ice above the half
+    pub elevation: Option<String>,  // 16-bit 1 channel, heights in meters
+    pub clouds: Option<String>,     // grayscale cloud cover, the first channel is used
+}
 
-    pub fn get_rgb(&self, u: f64, v: f64) -> RGB {
-        let w = (self.width as f64 * u) as usize;
-        let h = (self.height as f64 * v) as usize;
-        self.rgb_data[h * self.width + w]
+impl TexturePaths {
+    // https://www.solarsystemscope.com/textures/
+    // the specular and normal maps are left out when they are not in the directory
+    pub fn solar_system_scope(dir: &str) -> Self {
+        let optional = |name: &str| {
+            let path = format!("{dir}/{name}");
+            Path::new(&path).exists().then_some(path)
+        };
+        TexturePaths {
+            albedo: Some(format!("{dir}/2k_earth_daymap.jpg")),
+            specular: optional("2k_earth_specular_map.tif"),
+            normal: optional("2k_earth_normal_map.tif"),
+            land_cover: None,
+            spectral_library: None,
+            snow_mask: None,
+            elevation: None,
+            clouds: None,
+        }
     }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Filter {
+    Bilinear,
+    Mipmap, // trilinear, level chosen by the ray footprint
+}
+
+pub struct Texture {
+    pub albedo: MipMap,
+    pub specular: Option<MipMap>,
+    pub normal: Option<MipMap>,
+    pub land_cover: Option<(Map<u8>, SpectralLibrary)>, // used instead of albedo
+    pub snow_mask: Option<Map<u8>>,
+    pub elevation: Option<Terrain>,
+    pub clouds: Option<Map<u8>>,
+    pub filter: Filter,
+    gamma_table: Vec<f64>,
+}
+
+impl Texture {
+    // PNG, JPEG and TIFF are accepted for each map
+    pub fn load(paths: &TexturePaths) -> Result<Self> {
+        let gamma_table = gamma_table();
+        let linear = |c: u8| gamma_table[c as usize];
+
+        let albedo = match &paths.albedo {
+            Some(path) => MipMap::new(load_map(path, &[1, 3, 4])?.to_u8(), &linear, &encode_gamma),
+            None => MipMap::new(uniform_map(0.1), &linear, &encode_gamma),
+        };
+        let specular = match &paths.specular {
+            Some(path) => Some(MipMap::new(
+                load_map(path, &[1, 2, 3, 4])?.to_u8(),
+                &decode_raw,
+                &encode_raw,
+            )),
+            None => None,
+        };
+        let normal = match &paths.normal {
+            Some(path) => Some(MipMap::new(
+                load_map(path, &[3, 4])?.to_u8(),
+                &decode_raw,
+                &encode_raw,
+            )),
+            None => None,
+        };
+        let land_cover = match (&paths.land_cover, &paths.spectral_library) {
+            (Some(map), Some(library)) => Some((
+                load_map(map, &[1])?.to_u8(),
+                SpectralLibrary::load(library)?,
+            )),
+            (None, None) => None,
+            _ => {
+                return Err(Error::Texture(
+                    "land cover map and spectral library must be given together".to_string(),
+                ));
+            }
+        };
 
-    pub fn get_property(&self, u: f64, v: f64) -> (u8, Vec3) {
-        let w = (self.width as f64 * u) as usize;
-        let h = (self.height as f64 * v) as usize;
-        let id = h * self.width + w;
+        let snow_mask = match &paths.snow_mask {
+            Some(path) => Some(load_map(path, &[1])?.to_u8()),
+            None => None,
+        };
 
-        let specular = if let Some(sdata) = &self.specular_data {
-            sdata[id]
+        let elevation = match &paths.elevation {
+            Some(path) => Some(Terrain::new(load_elevation(path)?)),
+            None => None,
+        };
+
+        let clouds = match &paths.clouds {
+            Some(path) => Some(load_map(path, &[1, 3, 4])?.to_u8()),
+            None => None,
+        };
+
+        Ok(Texture {
+            albedo,
+            specular,
+            normal,
+            land_cover,
+            snow_mask,
+            elevation,
+            clouds,
+            filter: Filter::Mipmap,
+            gamma_table,
+        })
+    }
+
+    // an Earth without textures
+    pub fn uniform(albedo: f64) -> Self {
+        let gamma_table = gamma_table();
+        let linear = |c: u8| gamma_table[c as usize];
+        Texture {
+            albedo: MipMap::new(uniform_map(albedo), &linear, &encode_gamma),
+            specular: None,
+            normal: None,
+            land_cover: None,
+            snow_mask: None,
+            elevation: None,
+            clouds: None,
+            filter: Filter::Mipmap,
+            gamma_table,
+        }
+    }
+
+    fn footprint(&self, footprint: f64) -> f64 {
+        match self.filter {
+            Filter::Bilinear => 0.,
+            Filter::Mipmap => footprint,
+        }
+    }
+
+    // footprint: width of the ray on the surface in v (0 - 1)
+    pub fn get_rgb(&self, u: f64, v: f64, footprint: f64) -> RGB {
+        let footprint = self.footprint(footprint);
+        let linear = |c: u8| self.gamma_table[c as usize];
+        let t = self.albedo.sample(u, v, footprint, &linear);
+        if self.albedo.channels() < 3 {
+            Vec3::new(t[0])
+        } else {
+            Vec3(t[0], t[1], t[2])
+        }
+    }
+
+    // None if there is no land cover map or the class is unknown
+    pub fn get_class(&self, u: f64, v: f64) -> Option<LandCover> {
+        let (map, _) = self.land_cover.as_ref()?;
+        LandCover::from_index(map.nearest(u, v)[0])
+    }
+
+    // None if there is no snow mask
+    pub fn get_snow(&self, u: f64, v: f64) -> Option<bool> {
+        let map = self.snow_mask.as_ref()?;
+        Some(map.bilinear(u, v, &decode_raw)[0] >= 127.5)
+    }
+
+    // 0 - 1, None if there is no cloud cover map
+    pub fn get_cloud_cover(&self, u: f64, v: f64) -> Option<f64> {
+        let map = self.clouds.as_ref()?;
+        Some(map.bilinear(u, v, &decode_raw)[0] / 255.)
+    }
+
+    pub fn get_class_reflectance(&self, u: f64, v: f64, wavelength: f64) -> Option<f64> {
+        let (_, library) = self.land_cover.as_ref()?;
+        Some(library.reflectance(self.get_class(u, v)?, wavelength))
+    }
+
+    pub fn get_property(&self, u: f64, v: f64, footprint: f64) -> (u8, Vec3) {
+        let footprint = self.footprint(footprint);
+        let specular = if let Some(map) = &self.specular {
+            map.sample(u, v, footprint, &decode_raw)[0].round() as u8
         } else {
             0
         };
 
-        let normal = if let Some(ndata) = &self.normal_data {
-            ndata[id]
+        let normal = if let Some(map) = &self.normal {
+            let t = map.sample(u, v, footprint, &decode_raw);
+            let r = t[0] / 255.;
+            let g = t[1] / 255.;
+            let b = t[2] / 255.;
+            Vec3(r * 2. - 1., g * 2. - 1., b * 2. - 1.)
         } else {
             Vec3(0., 0., 1.)
         };
@@ -53,74 +352,213 @@ impl Texture {
     }
 }
 
-fn load_jpg() -> (Vec<RGB>, usize, usize) {
-    // https://www.solarsystemscope.com/textures/
-    let file = File::open("assets/2k_earth_daymap.jpg");
-    if let Err(_) = file {
-        println!("rgb-texture not found");
-        return (vec![Vec3::new(0.1)], 1, 1);
+impl Default for Texture {
+    fn default() -> Self {
+        Self::uniform(0.1)
     }
+}
 
-    let file = BufReader::new(file.unwrap());
-    let mut decoder = JpegDecoder::new(file);
-    let pixels = decoder.decode().expect("failed to decode image");
-    let metadata = decoder.info().unwrap();
+impl Hash for Texture {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.albedo.hash(state);
+        self.specular.hash(state);
+        self.normal.hash(state);
+        self.land_cover.hash(state);
+        self.snow_mask.hash(state);
+        self.elevation.hash(state);
+        self.clouds.hash(state);
+        self.filter.hash(state);
+    }
+}
 
-    let width = metadata.width as usize;
-    let height = metadata.height as usize;
-    let mut data = vec![Vec3::zero(); width * height];
+fn gamma_table() -> Vec<f64> {
+    (0..256).map(|c| (c as f64 / 255.).powf(2.2)).collect()
+}
+
+fn encode_gamma(c: f64) -> u8 {
+    (c.powf(1. / 2.2) * 255.).round() as u8
+}
+
+fn decode_raw(c: u8) -> f64 {
+    c as f64
+}
+
+fn encode_raw(c: f64) -> u8 {
+    c.round() as u8
+}
 
-    for i in 0..width * height {
-        let r = pixels[3 * i] as f64 / 255.;
-        let g = pixels[3 * i + 1] as f64 / 255.;
-        let b = pixels[3 * i + 2] as f64 / 255.;
-        data[i] = Vec3(r.powf(2.2), g.powf(2.2), b.powf(2.2));
+fn uniform_map(albedo: f64) -> Map<u8> {
+    Map {
+        data: vec![encode_gamma(albedo); 3],
+        channels: 3,
+        width: 1,
+        height: 1,
     }
+}
 
-    (data, width, height)
+// 8-bit images are scaled to 16-bit
+pub fn load_map(path: &str, channels: &[usize]) -> Result<Map<u16>> {
+    load_map_bits(path, channels).map(|(map, _)| map)
 }
 
-fn load_tiff(w: usize, h: usize) -> (Option<Vec<u8>>, Option<Vec<RGB>>) {
-    let specular_file = File::open("assets/2k_earth_specular_map.tif");
-    if let Err(_) = specular_file {
-        println!("specular-texture not found");
-        return (None, None);
+// heights in meters, 8-bit samples would be stretched to 0 - 65535
+fn load_elevation(path: &str) -> Result<Map<u16>> {
+    let (map, bits) = load_map_bits(path, &[1])?;
+    if bits != 16 {
+        return Err(Error::Texture(format!(
+            "{path} is a {bits}-bit image, a 16-bit elevation model expected"
+        )));
+    }
+    if map.width > MAX_SIZE || map.height > MAX_SIZE {
+        return Err(Error::Texture(format!(
+            "{path} is larger than {MAX_SIZE} texels"
+        )));
     }
-    let specular_file = specular_file.unwrap();
-    let mut decoder = Decoder::new(specular_file).unwrap();
+    Ok(map)
+}
+
+// the map and the bits per sample of the file
+fn load_map_bits(path: &str, channels: &[usize]) -> Result<(Map<u16>, u32)> {
+    let mut magic = [0; 4];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .map_err(|e| Error::io(path, e))?;
 
-    let specular_pixels = if let DecodingResult::U8(v) = decoder.read_image().unwrap() {
-        v
-    } else {
-        return (None, None);
+    let (map, bits) = match magic {
+        [0x89, b'P', b'N', b'G'] => load_png(path)?,
+        [0xff, 0xd8, ..] => load_jpg(path)?,
+        [b'I', b'I', 42, 0] | [b'M', b'M', 0, 42] => load_tiff(path)?,
+        _ => return Err(Error::decode(path, "not a PNG, JPEG or TIFF image")),
     };
 
-    let mut specular_data = vec![0; w * h];
-    for i in 0..w * h {
-        specular_data[i] = specular_pixels[3 * i];
+    if !channels.contains(&map.channels) {
+        return Err(Error::Texture(format!(
+            "{path} has {} channels, {channels:?} expected",
+            map.channels
+        )));
     }
-
-    let normal_file = File::open("assets/2k_earth_normal_map.tif");
-    if let Err(_) = normal_file {
-        println!("normal-texture not found");
-        return (Some(specular_data), None);
+    if map.data.len() != map.width * map.height * map.channels || map.data.is_empty() {
+        return Err(Error::Texture(format!(
+            "{path} has an unexpected data size"
+        )));
     }
-    let normal_file = normal_file.unwrap();
-    decoder = Decoder::new(normal_file).unwrap();
 
-    let normal_pixels = if let DecodingResult::U8(v) = decoder.read_image().unwrap() {
-        v
-    } else {
-        return (Some(specular_data), None);
+    Ok((map, bits))
+}
+
+fn load_jpg(path: &str) -> Result<(Map<u16>, u32)> {
+    let file = File::open(path).map_err(|e| Error::io(path, e))?;
+    let mut decoder = JpegDecoder::new(BufReader::new(file));
+    let pixels = decoder.decode().map_err(|e| Error::decode(path, e))?;
+    let metadata = decoder
+        .info()
+        .ok_or_else(|| Error::decode(path, "no image info"))?;
+
+    let width = metadata.width as usize;
+    let height = metadata.height as usize;
+    let map = Map {
+        channels: pixels.len() / (width * height).max(1),
+        data: pixels.iter().map(|p| *p as u16 * 257).collect(),
+        width,
+        height,
     };
+    Ok((map, 8))
+}
+
+fn load_png(path: &str) -> Result<(Map<u16>, u32)> {
+    let file = File::open(path).map_err(|e| Error::io(path, e))?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info().map_err(|e| Error::decode(path, e))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buf)
+        .map_err(|e| Error::decode(path, e))?;
+    buf.truncate(info.buffer_size());
 
-    let mut normal_data = vec![Vec3::zero(); w * h];
-    for i in 0..w * h {
-        let r = normal_pixels[3 * i] as f64 / 255.;
-        let g = normal_pixels[3 * i + 1] as f64 / 255.;
-        let b = normal_pixels[3 * i + 2] as f64 / 255.;
-        normal_data[i] = Vec3(r * 2. - 1., g * 2. - 1., b * 2. - 1.);
+    let (data, bits) = match info.bit_depth {
+        png::BitDepth::Sixteen => (
+            buf.chunks(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .collect(),
+            16,
+        ),
+        _ => (buf.iter().map(|p| *p as u16 * 257).collect(), 8),
+    };
+
+    let map = Map {
+        data,
+        channels: info.color_type.samples(),
+        width: info.width as usize,
+        height: info.height as usize,
+    };
+    Ok((map, bits))
+}
+
+fn load_tiff(path: &str) -> Result<(Map<u16>, u32)> {
+    let file = File::open(path).map_err(|e| Error::io(path, e))?;
+    let mut decoder = Decoder::new(BufReader::new(file)).map_err(|e| Error::decode(path, e))?;
+
+    let (width, height) = decoder.dimensions().map_err(|e| Error::decode(path, e))?;
+    let channels = match decoder.colortype().map_err(|e| Error::decode(path, e))? {
+        ColorType::Gray(8 | 16) => 1,
+        ColorType::GrayA(8 | 16) => 2,
+        ColorType::RGB(8 | 16) => 3,
+        ColorType::RGBA(8 | 16) => 4,
+        other => {
+            return Err(Error::Texture(format!(
+                "{path} has unsupported color type {other:?}"
+            )));
+        }
+    };
+
+    let (data, bits) = match decoder.read_image().map_err(|e| Error::decode(path, e))? {
+        DecodingResult::U8(v) => (v.iter().map(|p| *p as u16 * 257).collect(), 8),
+        DecodingResult::U16(v) => (v, 16),
+        // elevation models, heights below 0 are clipped
+        DecodingResult::I16(v) => (v.iter().map(|p| (*p).max(0) as u16).collect(), 16),
+        _ => return Err(Error::Texture(format!("{path} is not an 8/16-bit image"))),
+    };
+
+    let map = Map {
+        data,
+        channels,
+        width: width as usize,
+        height: height as usize,
+    };
+    Ok((map, bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_gray_png(name: &str, depth: png::BitDepth, data: &[u8]) -> String {
+        let path = std::env::temp_dir().join(name);
+        let file = File::create(&path).unwrap();
+        let mut encoder = png::Encoder::new(file, 2, 2);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(depth);
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(data)
+            .unwrap();
+        path.to_string_lossy().into_owned()
     }
 
-    (Some(specular_data), Some(normal_data))
+    // 8-bit samples are not read as heights
+    #[test]
+    fn elevation_must_be_16_bit() {
+        let path = write_gray_png(
+            "sky-simulator-dem8.png",
+            png::BitDepth::Eight,
+            &[0, 1, 2, 3],
+        );
+        assert!(matches!(load_elevation(&path), Err(Error::Texture(_))));
+
+        let data = [0, 0, 0, 100, 0x0b, 0xb8, 0, 0];
+        let path = write_gray_png("sky-simulator-dem16.png", png::BitDepth::Sixteen, &data);
+        assert_eq!(load_elevation(&path).unwrap().data, vec![0, 100, 3000, 0]);
+    }
 }
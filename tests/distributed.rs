@@ -0,0 +1,152 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::Duration,
+};
+
+use sky_simulator::{
+    Camera, Direction, Eye, RenderConfig, Scene, Texture,
+    checkpoint::config_hash,
+    distributed::{Distributed, work},
+    image::RenderedImage,
+    progress::{Progress, ProgressReporter, Statistics, WorkerEvent},
+    render,
+};
+
+fn config() -> RenderConfig {
+    let mut config = RenderConfig::new();
+    config.progress = None;
+    config.tile_size = 4;
+    config
+}
+
+// a free port for the coordinator
+fn free_addr() -> String {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string()
+}
+
+// until the coordinator listens
+fn connect(addr: &str) -> TcpStream {
+    for _ in 0..500 {
+        if let Ok(stream) = TcpStream::connect(addr) {
+            return stream;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("no coordinator on {addr}");
+}
+
+fn work_retrying(addr: &str, scene: &Scene, camera: &Camera, hash: u64) {
+    for _ in 0..500 {
+        if work(addr, scene, camera, hash).is_ok() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn assert_same(a: &RenderedImage, b: &RenderedImage) {
+    assert_eq!(a.xyz.len(), b.xyz.len());
+    for (a, b) in a.xyz.iter().zip(&b.xyz) {
+        assert_eq!(
+            [a.0.to_bits(), a.1.to_bits(), a.2.to_bits()],
+            [b.0.to_bits(), b.1.to_bits(), b.2.to_bits()]
+        );
+    }
+}
+
+struct Recorder(Arc<Mutex<Vec<WorkerEvent>>>);
+
+impl ProgressReporter for Recorder {
+    fn update(&self, _progress: &Progress) {}
+    fn finish(&self, _progress: &Progress, _stats: &Statistics) {}
+    fn worker_event(&self, event: &WorkerEvent) {
+        self.0.lock().unwrap().push(event.clone());
+    }
+}
+
+// workers on local threads give the same image as a single process
+#[test]
+fn workers_match_single_process() {
+    let texture = Texture::uniform(0.1);
+    let mut scene = Scene::new(3, &texture).unwrap();
+    let eye = Eye::new(12., 30., 1., Direction::South, 30.);
+    let camera = Camera::new(&eye, &mut scene, 8, 6, 0.2, 0.5, 2, 1).unwrap();
+    let local = render(&scene, &camera, &config()).unwrap();
+
+    let addr = free_addr();
+    let hash = config_hash(&scene, &camera);
+    let distributed = thread::scope(|s| {
+        for _ in 0..2 {
+            s.spawn(|| work_retrying(&addr, &scene, &camera, hash));
+        }
+        let mut config = config();
+        config.distributed = Some(Distributed::new(&addr, 2));
+        render(&scene, &camera, &config).unwrap()
+    });
+
+    assert_same(&local, &distributed);
+}
+
+// a peer with a broken handshake is dropped, and the tiles of a hung worker rendered elsewhere
+#[test]
+fn bad_peers_and_hung_workers_are_dropped() {
+    let texture = Texture::uniform(0.1);
+    let mut scene = Scene::new(3, &texture).unwrap();
+    let eye = Eye::new(12., 30., 1., Direction::South, 30.);
+    let camera = Camera::new(&eye, &mut scene, 8, 6, 0.2, 0.5, 2, 1).unwrap();
+    let local = render(&scene, &camera, &config()).unwrap();
+
+    let addr = free_addr();
+    let hash = config_hash(&scene, &camera);
+    let events = Arc::new(Mutex::new(vec![]));
+    let (handshaken, ready) = mpsc::channel();
+    let distributed = thread::scope(|s| {
+        s.spawn(|| {
+            let mut peer = connect(&addr);
+            peer.write_all(b"GET").unwrap();
+            drop(peer);
+
+            // accepted, then never answers
+            let mut hung = connect(&addr);
+            hung.write_all(b"SKYWRKR3").unwrap();
+            hung.write_all(&hash.to_le_bytes()).unwrap();
+            hung.write_all(&1u32.to_le_bytes()).unwrap();
+            let mut accepted = [0; 4];
+            hung.read_exact(&mut accepted).unwrap();
+            handshaken.send(()).unwrap();
+            thread::sleep(Duration::from_secs(3));
+        });
+        s.spawn(|| {
+            let ready = ready;
+            ready.recv().unwrap();
+            work_retrying(&addr, &scene, &camera, hash);
+        });
+
+        let mut config = config();
+        let mut dist = Distributed::new(&addr, 2);
+        dist.timeout = Duration::from_secs(1);
+        config.distributed = Some(dist);
+        config.progress = Some(Box::new(Recorder(events.clone())));
+        render(&scene, &camera, &config).unwrap()
+    });
+
+    assert_same(&local, &distributed);
+    let events = events.lock().unwrap();
+    assert!(
+        events
+            .iter()
+            .any(|e| matches!(e, WorkerEvent::Rejected { .. }))
+    );
+    assert!(
+        events
+            .iter()
+            .any(|e| matches!(e, WorkerEvent::Failed { .. }))
+    );
+}